@@ -0,0 +1,801 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pixelguard::compression::{
+    AvifSubsampling, BackupMode, CancellationToken, CompressionEngine, CompressionProgress,
+    CompressionSettings, CompressionStatus, ConflictPolicy, MetadataPolicy, OutputFormat,
+    OutputVariant, PngDeflater, PngFilter, ResizeFilter, ResizeMode, ResizeSettings, VisualTarget,
+    WebPMode,
+};
+use pixelguard::error::ErrorKind;
+use pixelguard::file::{FileManager, ImageFile, ScanOptions};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "pixelguard",
+    version,
+    about = "Lossless image compression tool"
+)]
+pub struct Cli {
+    /// Run headless instead of opening the GUI
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compress images from the command line
+    Compress(Box<CompressArgs>),
+    /// Put back the originals replaced by an in-place batch
+    Restore(RestoreArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RestoreArgs {
+    /// Manifest printed at the end of the in-place batch
+    manifest: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct CompressArgs {
+    /// Image files or folders to compress. Folders are searched recursively
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Only compress files in input folders matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip files and folders matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// How many folder levels below each input folder to search
+    #[arg(long, value_name = "LEVELS")]
+    max_depth: Option<usize>,
+
+    /// Include hidden files and folders when searching input folders
+    #[arg(long)]
+    hidden: bool,
+
+    /// Follow symbolic links when searching input folders
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = FormatArg::Png)]
+    format: FormatArg,
+
+    /// With --format auto, only try lossless encodings
+    #[arg(long)]
+    lossless_only: bool,
+
+    /// PNG optimization level (1-6)
+    #[arg(long)]
+    png_level: Option<u8>,
+
+    /// PNG row filters to try, comma separated (e.g. none,sub,entropy)
+    #[arg(long, value_delimiter = ',', value_parser = parse_png_filter)]
+    png_filter: Vec<PngFilter>,
+
+    /// Compress PNG data with Zopfli using this many iterations
+    #[arg(long, value_name = "ITERATIONS")]
+    zopfli: Option<u8>,
+
+    /// Write interlaced (Adam7) PNGs
+    #[arg(long)]
+    interlace: bool,
+
+    /// Disable PNG bit depth, color type, palette and grayscale reductions
+    #[arg(long)]
+    no_png_reductions: bool,
+
+    /// WebP quality (0-100)
+    #[arg(long)]
+    webp_quality: Option<f32>,
+
+    /// WebP encoding mode
+    #[arg(long, value_enum)]
+    webp_mode: Option<WebPModeArg>,
+
+    /// WebP encoder effort (0-6)
+    #[arg(long)]
+    webp_method: Option<u8>,
+
+    /// WebP near-lossless preprocessing (0 = most, 100 = none)
+    #[arg(long)]
+    webp_near_lossless: Option<u8>,
+
+    /// JPEG quality (1-100)
+    #[arg(long)]
+    jpeg_quality: Option<u8>,
+
+    /// AVIF quality (1-100)
+    #[arg(long)]
+    avif_quality: Option<f32>,
+
+    /// AVIF encoder speed (1 = slowest, 10 = fastest)
+    #[arg(long)]
+    avif_speed: Option<u8>,
+
+    /// AVIF chroma subsampling
+    #[arg(long, value_enum)]
+    avif_subsampling: Option<SubsamplingArg>,
+
+    /// JPEG XL quality (0-100, 100 = lossless)
+    #[arg(long)]
+    jxl_quality: Option<f32>,
+
+    /// JPEG XL encoder effort (1 = fastest, 9 = smallest)
+    #[arg(long)]
+    jxl_effort: Option<u8>,
+
+    /// Re-encode JPEG inputs instead of transcoding them losslessly to JPEG XL
+    #[arg(long)]
+    no_lossless_jpeg: bool,
+
+    /// Resize images to at most this many pixels wide before encoding
+    #[arg(long, value_name = "PIXELS")]
+    max_width: Option<u32>,
+
+    /// Resize images to at most this many pixels high before encoding
+    #[arg(long, value_name = "PIXELS")]
+    max_height: Option<u32>,
+
+    /// How images are fitted to --max-width and --max-height
+    #[arg(long, value_enum, default_value_t = ResizeModeArg::Fit)]
+    resize_mode: ResizeModeArg,
+
+    /// Enlarge images smaller than --max-width and --max-height
+    #[arg(long)]
+    upscale: bool,
+
+    /// Resampling filter used when resizing
+    #[arg(long, value_enum, default_value_t = ResizeFilterArg::Lanczos3)]
+    resize_filter: ResizeFilterArg,
+
+    /// Write a variant of every image at each of these widths, comma separated
+    /// (e.g. 480,960,1920). 0 keeps the original width
+    #[arg(long, value_delimiter = ',', value_name = "WIDTHS")]
+    variant_widths: Vec<u32>,
+
+    /// Write each variant width in each of these formats, comma separated
+    /// (e.g. avif,webp,jpeg). Defaults to --format
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FORMATS")]
+    variant_formats: Vec<FormatArg>,
+
+    /// Write a <picture> element listing each image's variants, as <stem>.html
+    #[arg(long)]
+    picture_html: bool,
+
+    /// Write a JSON manifest of each image's variants, as <stem>.json
+    #[arg(long)]
+    variant_json: bool,
+
+    /// Directory the compressed files are written to
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Output file name template, e.g. "{stem}.{hash8}.{ext}". Tokens: {stem} {ext}
+    /// {format} {width} {height} {quality} {hash8} {date}
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<String>,
+
+    /// What to do when an output file already exists
+    #[arg(long, value_enum, default_value_t = ConflictArg::Overwrite)]
+    on_conflict: ConflictArg,
+
+    /// Use the lowest quality whose output keeps at least this SSIM (0-1)
+    #[arg(long, value_name = "SSIM", conflicts_with_all = ["max_dssim", "max_size"])]
+    min_ssim: Option<f64>,
+
+    /// Use the lowest quality whose output stays within this DSSIM distance
+    #[arg(long, value_name = "DSSIM", conflicts_with = "max_size")]
+    max_dssim: Option<f64>,
+
+    /// Keep every output within SIZE, e.g. 200KB or 1.5MB, by searching for the
+    /// highest quality that fits
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Fail files that do not fit --max-size instead of downscaling them
+    #[arg(long, requires = "max_size")]
+    no_downscale: bool,
+
+    /// Report SSIM, PSNR and DSSIM of each output compared with its input
+    #[arg(long)]
+    metrics: bool,
+
+    /// Skip files whose output is not at least PERCENT smaller than the input
+    #[arg(long, value_name = "PERCENT")]
+    min_savings: Option<f32>,
+
+    /// Copy files skipped by --min-savings to the output directory unchanged
+    #[arg(long, requires = "min_savings")]
+    copy_skipped: bool,
+
+    /// Replace each input with its compressed version instead of writing to the
+    /// output directory
+    #[arg(long, conflicts_with_all = ["output", "name_template", "mirror"])]
+    in_place: bool,
+
+    /// Where --in-place keeps the originals it replaces
+    #[arg(long, value_enum, requires = "in_place")]
+    backup: Option<BackupArg>,
+
+    /// Folder for in-place backups and their restore manifests
+    #[arg(long, value_name = "DIR", requires = "in_place")]
+    backup_dir: Option<String>,
+
+    /// Recreate each file's folders relative to ROOT inside the output directory
+    #[arg(long, value_name = "ROOT")]
+    mirror: Option<PathBuf>,
+
+    /// Number of files compressed in parallel (0 = all cores)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Times a file is retried after a transient I/O error
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Metadata copied into outputs: strip keeps nothing, privacy keeps all but
+    /// location, serial numbers and thumbnails, keep keeps everything
+    #[arg(long, value_enum, default_value_t = MetadataArg::Strip)]
+    metadata: MetadataArg,
+
+    /// Same as --metadata keep
+    #[arg(long, conflicts_with = "metadata")]
+    preserve_metadata: bool,
+
+    /// Keep ICC color profiles, whatever --metadata says
+    #[arg(long)]
+    keep_icc: bool,
+
+    /// Keep author and copyright notices, whatever --metadata says
+    #[arg(long)]
+    keep_copyright: bool,
+
+    /// Remove GPS positions and place names
+    #[arg(long)]
+    strip_gps: bool,
+
+    /// Remove camera and lens serial numbers
+    #[arg(long)]
+    strip_serial_numbers: bool,
+
+    /// Remove embedded preview thumbnails
+    #[arg(long)]
+    strip_thumbnails: bool,
+
+    /// Only print failures and the final summary
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FormatArg {
+    Png,
+    Webp,
+    Jpeg,
+    Avif,
+    Jxl,
+    Gif,
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConflictArg {
+    Overwrite,
+    Skip,
+    Rename,
+    Fail,
+}
+
+impl From<ConflictArg> for ConflictPolicy {
+    fn from(policy: ConflictArg) -> Self {
+        match policy {
+            ConflictArg::Overwrite => ConflictPolicy::Overwrite,
+            ConflictArg::Skip => ConflictPolicy::Skip,
+            ConflictArg::Rename => ConflictPolicy::Rename,
+            ConflictArg::Fail => ConflictPolicy::Fail,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MetadataArg {
+    Strip,
+    Privacy,
+    Keep,
+}
+
+impl From<MetadataArg> for MetadataPolicy {
+    fn from(policy: MetadataArg) -> Self {
+        match policy {
+            MetadataArg::Strip => MetadataPolicy::strip_all(),
+            MetadataArg::Privacy => MetadataPolicy::privacy(),
+            MetadataArg::Keep => MetadataPolicy::keep_all(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ResizeModeArg {
+    Fit,
+    Fill,
+    Exact,
+}
+
+impl From<ResizeModeArg> for ResizeMode {
+    fn from(mode: ResizeModeArg) -> Self {
+        match mode {
+            ResizeModeArg::Fit => ResizeMode::Fit,
+            ResizeModeArg::Fill => ResizeMode::Fill,
+            ResizeModeArg::Exact => ResizeMode::Exact,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ResizeFilterArg {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilterArg> for ResizeFilter {
+    fn from(filter: ResizeFilterArg) -> Self {
+        match filter {
+            ResizeFilterArg::Nearest => ResizeFilter::Nearest,
+            ResizeFilterArg::Triangle => ResizeFilter::Triangle,
+            ResizeFilterArg::CatmullRom => ResizeFilter::CatmullRom,
+            ResizeFilterArg::Gaussian => ResizeFilter::Gaussian,
+            ResizeFilterArg::Lanczos3 => ResizeFilter::Lanczos3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BackupArg {
+    None,
+    Folder,
+    Trash,
+}
+
+impl From<BackupArg> for BackupMode {
+    fn from(mode: BackupArg) -> Self {
+        match mode {
+            BackupArg::None => BackupMode::None,
+            BackupArg::Folder => BackupMode::Folder,
+            BackupArg::Trash => BackupMode::Trash,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SubsamplingArg {
+    #[value(name = "444")]
+    Yuv444,
+    #[value(name = "422")]
+    Yuv422,
+    #[value(name = "420")]
+    Yuv420,
+}
+
+impl From<SubsamplingArg> for AvifSubsampling {
+    fn from(subsampling: SubsamplingArg) -> Self {
+        match subsampling {
+            SubsamplingArg::Yuv444 => AvifSubsampling::Yuv444,
+            SubsamplingArg::Yuv422 => AvifSubsampling::Yuv422,
+            SubsamplingArg::Yuv420 => AvifSubsampling::Yuv420,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum WebPModeArg {
+    Lossy,
+    Lossless,
+    NearLossless,
+}
+
+impl From<WebPModeArg> for WebPMode {
+    fn from(mode: WebPModeArg) -> Self {
+        match mode {
+            WebPModeArg::Lossy => WebPMode::Lossy,
+            WebPModeArg::Lossless => WebPMode::Lossless,
+            WebPModeArg::NearLossless => WebPMode::NearLossless,
+        }
+    }
+}
+
+impl From<FormatArg> for OutputFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Png => OutputFormat::Png,
+            FormatArg::Webp => OutputFormat::WebP,
+            FormatArg::Jpeg => OutputFormat::Jpeg,
+            FormatArg::Avif => OutputFormat::Avif,
+            FormatArg::Jxl => OutputFormat::JpegXl,
+            FormatArg::Gif => OutputFormat::Gif,
+            FormatArg::Auto => OutputFormat::Auto,
+        }
+    }
+}
+
+impl CompressArgs {
+    fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            max_depth: self.max_depth,
+            include_hidden: self.hidden,
+            follow_symlinks: self.follow_symlinks,
+        }
+    }
+
+    fn settings(&self) -> CompressionSettings {
+        let mut settings = CompressionSettings {
+            output_format: self.format.into(),
+            conflict_policy: self.on_conflict.into(),
+            measure_quality: self.metrics,
+            copy_original_on_skip: self.copy_skipped,
+            lossless_only: self.lossless_only,
+            in_place: self.in_place,
+            ..Default::default()
+        };
+
+        settings.metadata = if self.preserve_metadata {
+            MetadataPolicy::keep_all()
+        } else {
+            self.metadata.into()
+        };
+        settings.metadata.keep_icc |= self.keep_icc;
+        settings.metadata.keep_copyright |= self.keep_copyright;
+        settings.metadata.strip_gps |= self.strip_gps;
+        settings.metadata.strip_serial_numbers |= self.strip_serial_numbers;
+        settings.metadata.strip_thumbnails |= self.strip_thumbnails;
+
+        if let Some(level) = self.png_level {
+            settings.png_level = level;
+        }
+        settings.png.filters = self.png_filter.clone();
+        if let Some(iterations) = self.zopfli {
+            settings.png.deflater = PngDeflater::Zopfli { iterations };
+        }
+        settings.png.interlace = self.interlace;
+        if self.no_png_reductions {
+            settings.png.bit_depth_reduction = false;
+            settings.png.color_type_reduction = false;
+            settings.png.palette_reduction = false;
+            settings.png.grayscale_reduction = false;
+        }
+        if let Some(quality) = self.webp_quality {
+            settings.webp_quality = quality;
+        }
+        if let Some(mode) = self.webp_mode {
+            settings.webp.mode = mode.into();
+        }
+        if let Some(method) = self.webp_method {
+            settings.webp.method = method;
+        }
+        if let Some(level) = self.webp_near_lossless {
+            settings.webp.near_lossless = level;
+        }
+        if let Some(quality) = self.jpeg_quality {
+            settings.jpeg_quality = quality;
+        }
+        if let Some(quality) = self.avif_quality {
+            settings.avif_quality = quality;
+        }
+        if let Some(speed) = self.avif_speed {
+            settings.avif.speed = speed;
+        }
+        if let Some(subsampling) = self.avif_subsampling {
+            settings.avif.subsampling = subsampling.into();
+        }
+        if let Some(quality) = self.jxl_quality {
+            settings.jxl_quality = quality;
+        }
+        if let Some(effort) = self.jxl_effort {
+            settings.jxl.effort = effort;
+        }
+        settings.jxl.lossless_jpeg = !self.no_lossless_jpeg;
+        if self.max_width.is_some() || self.max_height.is_some() {
+            settings.resize_images = true;
+            settings.resize = ResizeSettings {
+                max_width: self.max_width.unwrap_or(0),
+                max_height: self.max_height.unwrap_or(0),
+                mode: self.resize_mode.into(),
+                never_upscale: !self.upscale,
+                filter: self.resize_filter.into(),
+            };
+        }
+        if !self.variant_widths.is_empty() || !self.variant_formats.is_empty() {
+            let widths = if self.variant_widths.is_empty() {
+                vec![0]
+            } else {
+                self.variant_widths.clone()
+            };
+            let formats = if self.variant_formats.is_empty() {
+                vec![self.format]
+            } else {
+                self.variant_formats.clone()
+            };
+            settings.generate_variants = true;
+            settings.variants = widths
+                .iter()
+                .flat_map(|&width| {
+                    formats.iter().map(move |&format| OutputVariant {
+                        width,
+                        format: format.into(),
+                    })
+                })
+                .collect();
+        }
+        settings.write_picture_html = self.picture_html;
+        settings.write_variant_json = self.variant_json;
+        if let Some(output) = &self.output {
+            settings.output_directory = output.clone();
+        }
+        if let Some(template) = &self.name_template {
+            settings.filename_template = template.clone();
+        }
+        if let Some(ssim) = self.min_ssim {
+            settings.match_visual_quality = true;
+            settings.visual_target = VisualTarget::MinSsim(ssim);
+        }
+        if let Some(dssim) = self.max_dssim {
+            settings.match_visual_quality = true;
+            settings.visual_target = VisualTarget::MaxDssim(dssim);
+        }
+        if let Some(size) = self.max_size {
+            settings.limit_file_size = true;
+            settings.max_file_size = size;
+        }
+        settings.downscale_to_fit = !self.no_downscale;
+        if let Some(percent) = self.min_savings {
+            settings.require_savings = true;
+            settings.min_savings_percent = percent;
+        }
+        if let Some(mode) = self.backup {
+            settings.backup_mode = mode.into();
+        }
+        if let Some(directory) = &self.backup_dir {
+            settings.backup_directory = directory.clone();
+        }
+        if let Some(root) = &self.mirror {
+            settings.mirror_structure = true;
+            settings.input_root = root.to_string_lossy().to_string();
+        }
+        if let Some(jobs) = self.jobs {
+            settings.worker_threads = jobs;
+        }
+        if let Some(retries) = self.retries {
+            settings.io_retries = retries;
+        }
+
+        settings
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", size))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1024.0,
+        "M" | "MB" => 1024.0 * 1024.0,
+        _ => return Err(format!("unknown size unit '{}', use B, KB or MB", unit)),
+    };
+    Ok((number * multiplier) as u64)
+}
+
+fn parse_png_filter(name: &str) -> Result<PngFilter, String> {
+    PngFilter::ALL
+        .into_iter()
+        .find(|filter| format!("{:?}", filter).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown PNG filter '{}'", name))
+}
+
+pub fn run(args: CompressArgs) -> ExitCode {
+    let settings = args.settings();
+    if let Err(error) = settings.validate() {
+        eprintln!("error: {}", error);
+        return ExitCode::from(2);
+    }
+
+    let scan_options = args.scan_options();
+    let mut unreadable = 0;
+    let mut files: Vec<ImageFile> = Vec::new();
+    for path in &args.inputs {
+        if path.is_dir() {
+            match FileManager::scan_directory(path, &scan_options) {
+                Ok(found) => files.extend(found),
+                Err(error) => {
+                    eprintln!("error: {}", error);
+                    return ExitCode::from(2);
+                }
+            }
+        } else if let Some(file) = FileManager::analyze_file(path.clone()) {
+            files.push(file);
+        } else {
+            eprintln!("error: cannot read {}", path.display());
+            unreadable += 1;
+        }
+    }
+
+    // Ctrl-C stops the batch between files instead of killing the process
+    // mid-write
+    let cancel_token = CancellationToken::new();
+    let handler_token = cancel_token.clone();
+    let _ = ctrlc::set_handler(move || {
+        eprintln!("cancelling...");
+        handler_token.cancel();
+    });
+
+    let (progress_sender, progress_receiver) = mpsc::channel();
+    let worker_token = cancel_token.clone();
+    let worker = std::thread::spawn(move || {
+        CompressionEngine::compress_files(files, settings, progress_sender, &worker_token)
+    });
+
+    let mut engine_failed = false;
+    for progress in progress_receiver {
+        match progress {
+            CompressionProgress::Processing {
+                current,
+                total,
+                filename,
+            } => {
+                if !args.quiet {
+                    println!("[{}/{}] {}", current, total, filename);
+                }
+            }
+            CompressionProgress::Progress(_) => {}
+            CompressionProgress::Complete => {}
+            CompressionProgress::Error(error) => {
+                eprintln!("error: {}", error);
+                engine_failed = true;
+            }
+        }
+    }
+
+    let results = match worker.join() {
+        Ok(results) => results,
+        Err(_) => {
+            eprintln!("error: compression thread panicked");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failed = unreadable;
+    let mut failures_by_kind = BTreeMap::new();
+    if unreadable > 0 {
+        failures_by_kind.insert(ErrorKind::Io, unreadable);
+    }
+    let mut original_total = 0;
+    let mut compressed_total = 0;
+    let mut saved_total = 0;
+
+    for result in &results {
+        match &result.status {
+            CompressionStatus::Success => {
+                original_total += result.original_size;
+                compressed_total += result.compressed_size;
+                saved_total += result.space_saved_bytes();
+                if !args.quiet {
+                    let quality = result
+                        .quality
+                        .map(|quality| format!(", quality {}", quality))
+                        .unwrap_or_default();
+                    println!(
+                        "  {} -> {} ({} -> {}, {:.1}%{})",
+                        result.input_path.display(),
+                        result.output_path.display(),
+                        FileManager::format_file_size(result.original_size),
+                        FileManager::format_file_size(result.compressed_size),
+                        result.compression_ratio * 100.0,
+                        quality
+                    );
+                    if let Some(metrics) = &result.metrics {
+                        println!("    {}", metrics);
+                    }
+                    if !result.metadata_removed.is_empty() {
+                        let removed: Vec<_> = result
+                            .metadata_removed
+                            .iter()
+                            .map(|kind| kind.name())
+                            .collect();
+                        println!("    removed {}", removed.join(", "));
+                    }
+                }
+            }
+            CompressionStatus::Failed(error) => {
+                failed += 1;
+                *failures_by_kind.entry(error.kind()).or_insert(0) += 1;
+                eprintln!("  failed: {}: {}", result.input_path.display(), error);
+            }
+            CompressionStatus::Skipped(reason) => {
+                if !args.quiet {
+                    println!("  skipped: {}: {}", result.input_path.display(), reason);
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} file(s), {} failed, {} -> {} (saved {})",
+        results.len() + unreadable,
+        failed,
+        FileManager::format_file_size(original_total),
+        FileManager::format_file_size(compressed_total),
+        FileManager::format_file_size(saved_total)
+    );
+    for (kind, count) in &failures_by_kind {
+        eprintln!("  {}: {}", kind, count);
+    }
+    if let Some(manifest) = results
+        .iter()
+        .find_map(|result| result.backup_manifest.as_ref())
+    {
+        println!(
+            "Originals backed up. Undo with: pixelguard restore {}",
+            manifest.display()
+        );
+    }
+
+    if cancel_token.is_cancelled() {
+        // Conventional exit status for a process interrupted by SIGINT
+        ExitCode::from(130)
+    } else if failed > 0 || engine_failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+pub fn restore(args: RestoreArgs) -> ExitCode {
+    match CompressionEngine::restore_backup(&args.manifest) {
+        Ok(restored) => {
+            println!("Restored {} file(s)", restored);
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_with_binary_units() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("200KB"), Ok(200 * 1024));
+        assert_eq!(parse_size(" 1.5 mb "), Ok(1536 * 1024));
+        assert_eq!(parse_size("2k"), Ok(2048));
+    }
+
+    #[test]
+    fn malformed_sizes_are_rejected() {
+        for size in ["", "KB", "1.2.3MB", "10GB", "-5KB"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use crate::compression::{ResizeSettings, resize};
+use crate::error::PixelGuardError;
+use crate::file::{ImageFile, ImageFormat};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, ImageError, RgbaImage};
+use std::fs::File;
+use std::io::BufReader;
+
+/// NeuQuant sampling speed for GIF palettes, 1 (best) to 30 (fastest). 10 is
+/// the speed the gif crate recommends.
+const GIF_QUANTIZER_SPEED: i32 = 10;
+
+/// Every frame of an animated image, composited onto the full canvas.
+#[derive(Clone)]
+pub(crate) struct Animation {
+    width: u32,
+    height: u32,
+    frames: Vec<AnimationFrame>,
+}
+
+#[derive(Clone)]
+struct AnimationFrame {
+    image: RgbaImage,
+    delay_ms: u32,
+}
+
+impl Animation {
+    /// Wraps a still image so it can go through the animation encoders.
+    pub(crate) fn still(image: RgbaImage) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            frames: vec![AnimationFrame { image, delay_ms: 0 }],
+        }
+    }
+
+    /// Width and height of the canvas.
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Resizes every frame as configured.
+    pub(crate) fn resize(&mut self, settings: &ResizeSettings) {
+        for frame in &mut self.frames {
+            let image = std::mem::take(&mut frame.image);
+            frame.image = resize::resize(image.into(), settings).into_rgba8();
+        }
+        if let Some(first) = self.frames.first() {
+            (self.width, self.height) = first.image.dimensions();
+        }
+    }
+
+    fn push_frame(&mut self, buffer: RgbaImage, left: u32, top: u32, delay_ms: u32) {
+        let previous = self.frames.last_mut().unwrap();
+        let image = if buffer.dimensions() == (self.width, self.height) {
+            buffer
+        } else {
+            let mut canvas = previous.image.clone();
+            image::imageops::replace(&mut canvas, &buffer, left.into(), top.into());
+            canvas
+        };
+
+        // Identical frames only add to the time the previous one is shown
+        if image == previous.image {
+            previous.delay_ms += delay_ms;
+        } else {
+            self.frames.push(AnimationFrame { image, delay_ms });
+        }
+    }
+
+    fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    fn total_duration_ms(&self) -> u32 {
+        self.frames.iter().map(|frame| frame.delay_ms).sum()
+    }
+}
+
+/// Decodes every frame of an animated GIF, APNG or WebP.
+///
+/// Returns `None` for formats that cannot be animated and for files that
+/// hold a single frame, which the still image encoders handle.
+pub(crate) fn decode(file: &ImageFile) -> Result<Option<Animation>, PixelGuardError> {
+    let open = || {
+        File::open(&file.path)
+            .map(BufReader::new)
+            .map_err(|e| PixelGuardError::read(&file.path, e))
+    };
+    let decode_error = |e: ImageError| PixelGuardError::decode(&file.path, e);
+
+    let frames = match file.format {
+        ImageFormat::Gif => GifDecoder::new(open()?)
+            .map_err(decode_error)?
+            .into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(open()?).map_err(decode_error)?;
+            if !decoder.is_apng().map_err(decode_error)? {
+                return Ok(None);
+            }
+            decoder.apng().map_err(decode_error)?.into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(open()?).map_err(decode_error)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let mut animation: Option<Animation> = None;
+    for frame in frames {
+        let frame = frame.map_err(decode_error)?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let delay_ms = numerator / denominator.max(1);
+        let (left, top) = (frame.left(), frame.top());
+        let buffer = frame.into_buffer();
+
+        match animation.as_mut() {
+            Some(animation) => animation.push_frame(buffer, left, top, delay_ms),
+            None => {
+                let mut first = Animation::still(buffer);
+                first.frames[0].delay_ms = delay_ms;
+                animation = Some(first);
+            }
+        }
+    }
+
+    Ok(animation.filter(Animation::is_animated))
+}
+
+/// Encodes `animation` as a looping GIF.
+///
+/// After the first frame only the rectangle that changed is stored, with
+/// unchanged pixels inside it made transparent so they compress well. If a
+/// pixel ever turns transparent that trick would show the previous frame
+/// through it, so such animations store every frame in full instead.
+pub(crate) fn encode_gif(animation: &Animation) -> Result<Vec<u8>, PixelGuardError> {
+    let to_u16 = |value: u32| {
+        u16::try_from(value).map_err(|_| {
+            PixelGuardError::Unsupported(
+                "Image is too large for GIF (max 65535 pixels)".to_string(),
+            )
+        })
+    };
+    let gif_error = |e: gif::EncodingError| PixelGuardError::encode("GIF", e);
+
+    let reveals_transparency = animation.frames.windows(2).any(|pair| {
+        pair[0]
+            .image
+            .pixels()
+            .zip(pair[1].image.pixels())
+            .any(|(before, after)| before[3] != 0 && after[3] == 0)
+    });
+
+    let mut output = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(
+            &mut output,
+            to_u16(animation.width)?,
+            to_u16(animation.height)?,
+            &[],
+        )
+        .map_err(gif_error)?;
+        if animation.is_animated() {
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(gif_error)?;
+        }
+
+        let mut previous: Option<&RgbaImage> = None;
+        for frame in &animation.frames {
+            let region = match previous {
+                Some(previous) if !reveals_transparency => changed_region(previous, &frame.image),
+                _ => Region::full(&frame.image),
+            };
+
+            let mut pixels = Vec::with_capacity(region.width as usize * region.height as usize * 4);
+            for y in region.y..region.y + region.height {
+                for x in region.x..region.x + region.width {
+                    let pixel = frame.image.get_pixel(x, y);
+                    let unchanged = !reveals_transparency
+                        && previous.is_some_and(|previous| previous.get_pixel(x, y) == pixel);
+                    if unchanged || pixel[3] == 0 {
+                        pixels.extend_from_slice(&[0, 0, 0, 0]);
+                    } else {
+                        pixels.extend_from_slice(&pixel.0);
+                    }
+                }
+            }
+
+            let mut gif_frame = gif::Frame::from_rgba_speed(
+                to_u16(region.width)?,
+                to_u16(region.height)?,
+                &mut pixels,
+                GIF_QUANTIZER_SPEED,
+            );
+            gif_frame.left = to_u16(region.x)?;
+            gif_frame.top = to_u16(region.y)?;
+            gif_frame.delay = u16::try_from(frame.delay_ms.div_ceil(10)).unwrap_or(u16::MAX);
+            gif_frame.dispose = if reveals_transparency {
+                gif::DisposalMethod::Background
+            } else {
+                gif::DisposalMethod::Keep
+            };
+            encoder.write_frame(&gif_frame).map_err(gif_error)?;
+
+            previous = Some(&frame.image);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Encodes `animation` as a looping APNG, storing only the changed rectangle
+/// of each frame after the first.
+pub(crate) fn encode_apng(animation: &Animation) -> Result<Vec<u8>, PixelGuardError> {
+    let png_error = |e: png::EncodingError| PixelGuardError::encode("APNG", e);
+
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, animation.width, animation.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+    encoder
+        .set_animated(animation.frames.len() as u32, 0)
+        .map_err(png_error)?;
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    let mut previous: Option<&RgbaImage> = None;
+    for frame in &animation.frames {
+        let region = match previous {
+            Some(previous) => changed_region(previous, &frame.image),
+            None => Region::full(&frame.image),
+        };
+        let pixels = image::imageops::crop_imm(
+            &frame.image,
+            region.x,
+            region.y,
+            region.width,
+            region.height,
+        )
+        .to_image();
+
+        let (numerator, denominator) = match u16::try_from(frame.delay_ms) {
+            Ok(delay_ms) => (delay_ms, 1000),
+            Err(_) => (u16::try_from(frame.delay_ms / 1000).unwrap_or(u16::MAX), 1),
+        };
+        writer
+            .set_frame_delay(numerator, denominator)
+            .map_err(png_error)?;
+        // Move to the origin first so the new size always fits the canvas
+        writer.set_frame_position(0, 0).map_err(png_error)?;
+        writer
+            .set_frame_dimension(region.width, region.height)
+            .map_err(png_error)?;
+        writer
+            .set_frame_position(region.x, region.y)
+            .map_err(png_error)?;
+        writer
+            .set_blend_op(png::BlendOp::Source)
+            .map_err(png_error)?;
+        writer
+            .set_dispose_op(png::DisposeOp::None)
+            .map_err(png_error)?;
+        writer.write_image_data(&pixels).map_err(png_error)?;
+
+        previous = Some(&frame.image);
+    }
+    writer.finish().map_err(png_error)?;
+
+    Ok(output)
+}
+
+/// Encodes `animation` as a looping animated WebP. libwebp finds the changed
+/// rectangles itself.
+pub(crate) fn encode_webp(
+    animation: &Animation,
+    config: &webp::WebPConfig,
+) -> Result<Vec<u8>, PixelGuardError> {
+    let mut encoder = webp::AnimEncoder::new(animation.width, animation.height, config);
+    encoder.set_loop_count(0);
+
+    let mut timestamp = 0;
+    for frame in &animation.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.image.as_raw(),
+            animation.width,
+            animation.height,
+            timestamp,
+        ));
+        timestamp += frame.delay_ms as i32;
+    }
+
+    let mut encoded = encoder
+        .try_encode()
+        .map_err(|e| PixelGuardError::encode_message("WebP", format!("{:?}", e)))?
+        .to_vec();
+    set_last_webp_frame_duration(&mut encoded, animation.total_duration_ms());
+    Ok(encoded)
+}
+
+/// The webp crate closes the animation with a zero timestamp, which libwebp
+/// rejects, so the last frame gets the average duration of the others. This
+/// rewrites the last `ANMF` chunk so the animation keeps its total length.
+fn set_last_webp_frame_duration(data: &mut [u8], total_duration_ms: u32) {
+    const DURATION_OFFSET: usize = 8 + 12;
+
+    let read_u24 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+
+    let mut frames = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if &data[offset..offset + 4] == b"ANMF" && offset + DURATION_OFFSET + 3 <= data.len() {
+            frames.push(offset + DURATION_OFFSET);
+        }
+        // Chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+    }
+
+    let Some((&last, others)) = frames.split_last() else {
+        return;
+    };
+    let others_ms: u32 = others
+        .iter()
+        .map(|&duration| read_u24(&data[duration..]))
+        .sum();
+    let last_ms = total_duration_ms.saturating_sub(others_ms).min(0xFF_FFFF);
+    data[last..last + 3].copy_from_slice(&last_ms.to_le_bytes()[..3]);
+}
+
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Region {
+    fn full(image: &RgbaImage) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        }
+    }
+}
+
+/// Returns the smallest rectangle containing every pixel that differs
+/// between `previous` and `current`, or a single pixel if none do.
+fn changed_region(previous: &RgbaImage, current: &RgbaImage) -> Region {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in current.enumerate_pixels() {
+        if previous.get_pixel(x, y) != pixel {
+            let (min_x, min_y, max_x, max_y) = bounds.get_or_insert((x, y, x, y));
+            *min_x = (*min_x).min(x);
+            *min_y = (*min_y).min(y);
+            *max_x = (*max_x).max(x);
+            *max_y = (*max_y).max(y);
+        }
+    }
+
+    let (min_x, min_y, max_x, max_y) = bounds.unwrap_or((0, 0, 0, 0));
+    Region {
+        x: min_x,
+        y: min_y,
+        width: max_x - min_x + 1,
+        height: max_y - min_y + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FileManager;
+    use image::Rgba;
+
+    /// A red frame shown for 100 ms, then a blue one shown for 250 ms.
+    fn two_frames() -> Animation {
+        let frame = |color, delay_ms| AnimationFrame {
+            image: RgbaImage::from_pixel(16, 16, Rgba(color)),
+            delay_ms,
+        };
+        Animation {
+            width: 16,
+            height: 16,
+            frames: vec![frame([255, 0, 0, 255], 100), frame([0, 0, 255, 255], 250)],
+        }
+    }
+
+    /// Writes `data` to a file named `name` and decodes it again.
+    fn round_trip(name: &str, data: &[u8]) -> Animation {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(name);
+        std::fs::write(&path, data).unwrap();
+        let file = FileManager::analyze_file(path).unwrap();
+        decode(&file).unwrap().expect("decoded as a still image")
+    }
+
+    /// Checks the frame count, delays and colors of a decoded
+    /// [`two_frames`], allowing each channel to be off by `tolerance`.
+    fn assert_matches_two_frames(decoded: &Animation, tolerance: u8) {
+        assert_eq!(decoded.dimensions(), (16, 16));
+        let delays: Vec<_> = decoded.frames.iter().map(|frame| frame.delay_ms).collect();
+        assert_eq!(delays, [100, 250]);
+        for (frame, expected) in decoded.frames.iter().zip(&two_frames().frames) {
+            let (actual, expected) = (frame.image.get_pixel(8, 8), expected.image.get_pixel(8, 8));
+            let close = actual
+                .0
+                .iter()
+                .zip(expected.0)
+                .all(|(a, b)| a.abs_diff(b) <= tolerance);
+            assert!(close, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn gif_keeps_frames_and_delays() {
+        let decoded = round_trip("anim.gif", &encode_gif(&two_frames()).unwrap());
+        assert_matches_two_frames(&decoded, 0);
+    }
+
+    #[test]
+    fn apng_keeps_frames_and_delays() {
+        let decoded = round_trip("anim.png", &encode_apng(&two_frames()).unwrap());
+        assert_matches_two_frames(&decoded, 0);
+    }
+
+    #[test]
+    fn webp_keeps_frames_and_delays() {
+        let mut config = webp::WebPConfig::new().unwrap();
+        config.lossless = 1;
+        config.exact = 1;
+        let decoded = round_trip("anim.webp", &encode_webp(&two_frames(), &config).unwrap());
+        // The decoder blends later frames onto the canvas with a rounding error
+        assert_matches_two_frames(&decoded, 1);
+    }
+
+    #[test]
+    fn single_frames_are_not_animations() {
+        let still = Animation::still(RgbaImage::new(16, 16));
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("still.gif");
+        std::fs::write(&path, encode_gif(&still).unwrap()).unwrap();
+        let file = FileManager::analyze_file(path).unwrap();
+        assert!(decode(&file).unwrap().is_none());
+    }
+}
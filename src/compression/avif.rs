@@ -0,0 +1,195 @@
+use crate::compression::{AvifSettings, AvifSubsampling};
+use crate::error::PixelGuardError;
+use image::RgbaImage;
+use rav1e::prelude::*;
+
+/// BT.601 luma coefficients. Safari assumes this matrix when an AVIF has no
+/// `colr` box, so it is the most compatible choice.
+const BT601: [f32; 3] = [0.299, 0.587, 0.114];
+
+/// Encodes `img` as a still AVIF image with rav1e, with `exif` attached if
+/// given. The container has no place for XMP, and the pixels are tagged as
+/// sRGB, so ICC profiles are not kept.
+///
+/// ravif only writes 4:4:4 color, so the color conversion, chroma subsampling
+/// and container are handled here to support 4:2:2 and 4:2:0 as well.
+pub(crate) fn encode(
+    img: &RgbaImage,
+    quality: f32,
+    settings: &AvifSettings,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, PixelGuardError> {
+    let (width, height) = img.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let (x_dec, y_dec) = match settings.subsampling {
+        AvifSubsampling::Yuv444 => (false, false),
+        AvifSubsampling::Yuv422 => (true, false),
+        AvifSubsampling::Yuv420 => (true, true),
+    };
+    let (chroma_sampling, seq_profile) = match settings.subsampling {
+        AvifSubsampling::Yuv444 => (ChromaSampling::Cs444, 1),
+        AvifSubsampling::Yuv422 => (ChromaSampling::Cs422, 2),
+        AvifSubsampling::Yuv420 => (ChromaSampling::Cs420, 0),
+    };
+
+    let planes = ycbcr_planes(img, x_dec, y_dec);
+    let color = encode_av1(
+        width,
+        height,
+        chroma_sampling,
+        quality_to_quantizer(quality),
+        settings.speed,
+        &planes,
+    )?;
+
+    let alpha = if img.pixels().any(|pixel| pixel[3] != u8::MAX) {
+        let alpha_plane = Plane {
+            data: img.pixels().map(|pixel| pixel[3]).collect(),
+            stride: width,
+        };
+        // Edges of transparent areas are very visible, so alpha gets a higher quality
+        let alpha_quality = (quality + 100.0) / 2.0;
+        Some(encode_av1(
+            width,
+            height,
+            ChromaSampling::Cs400,
+            quality_to_quantizer(alpha_quality),
+            settings.speed,
+            &[alpha_plane],
+        )?)
+    } else {
+        None
+    };
+
+    let mut container = avif_serialize::Aviffy::new();
+    container
+        .set_chroma_subsampling((x_dec, y_dec))
+        .set_seq_profile(seq_profile)
+        .matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+        .full_color_range(true);
+    if let Some(exif) = exif {
+        container.set_exif(exif.to_vec());
+    }
+    Ok(container.to_vec(&color, alpha.as_deref(), width as u32, height as u32, 8))
+}
+
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+/// Converts RGB to full range BT.601 Y, Cb and Cr planes, averaging chroma
+/// over each subsampled block.
+fn ycbcr_planes(img: &RgbaImage, x_dec: bool, y_dec: bool) -> [Plane; 3] {
+    let (width, height) = img.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let chroma_width = (width + x_dec as usize) >> x_dec as usize;
+    let chroma_height = (height + y_dec as usize) >> y_dec as usize;
+
+    let mut luma = Vec::with_capacity(width * height);
+    let mut cb_sum = vec![0.0f32; chroma_width * chroma_height];
+    let mut cr_sum = vec![0.0f32; chroma_width * chroma_height];
+    let mut samples = vec![0u8; chroma_width * chroma_height];
+
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+        let y_value = BT601[0] * r + BT601[1] * g + BT601[2] * b;
+        luma.push(y_value.round().clamp(0.0, 255.0) as u8);
+
+        let index = (y as usize >> y_dec as usize) * chroma_width + (x as usize >> x_dec as usize);
+        cb_sum[index] += (b - y_value) * 0.5 / (1.0 - BT601[2]) + 128.0;
+        cr_sum[index] += (r - y_value) * 0.5 / (1.0 - BT601[0]) + 128.0;
+        samples[index] += 1;
+    }
+
+    let average = |sums: Vec<f32>| -> Vec<u8> {
+        sums.iter()
+            .zip(&samples)
+            .map(|(sum, &count)| (sum / f32::from(count)).round().clamp(0.0, 255.0) as u8)
+            .collect()
+    };
+
+    [
+        Plane {
+            data: luma,
+            stride: width,
+        },
+        Plane {
+            data: average(cb_sum),
+            stride: chroma_width,
+        },
+        Plane {
+            data: average(cr_sum),
+            stride: chroma_width,
+        },
+    ]
+}
+
+fn encode_av1(
+    width: usize,
+    height: usize,
+    chroma_sampling: ChromaSampling,
+    quantizer: u8,
+    speed: u8,
+    planes: &[Plane],
+) -> Result<Vec<u8>, PixelGuardError> {
+    let color_description =
+        (chroma_sampling != ChromaSampling::Cs400).then_some(ColorDescription {
+            color_primaries: ColorPrimaries::BT709,
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            matrix_coefficients: MatrixCoefficients::BT601,
+        });
+
+    let config = Config::new().with_encoder_config(EncoderConfig {
+        width,
+        height,
+        bit_depth: 8,
+        chroma_sampling,
+        pixel_range: PixelRange::Full,
+        color_description,
+        still_picture: true,
+        quantizer: quantizer.into(),
+        min_quantizer: quantizer,
+        speed_settings: SpeedSettings::from_preset(speed),
+        ..Default::default()
+    });
+
+    let mut context: Context<u8> = config
+        .new_context()
+        .map_err(|e| PixelGuardError::encode("AVIF", e))?;
+
+    let mut frame = context.new_frame();
+    for (target, plane) in frame.planes.iter_mut().zip(planes) {
+        target.copy_from_raw_u8(&plane.data, plane.stride, 1);
+    }
+
+    context
+        .send_frame(frame)
+        .map_err(|e| PixelGuardError::encode_message("AVIF", format!("{:?}", e)))?;
+    context.flush();
+
+    let mut output = Vec::new();
+    loop {
+        match context.receive_packet() {
+            Ok(mut packet) => output.append(&mut packet.data),
+            Err(EncoderStatus::Encoded) => {}
+            Err(EncoderStatus::LimitReached) => break,
+            Err(e) => return Err(PixelGuardError::encode_message("AVIF", format!("{:?}", e))),
+        }
+    }
+    Ok(output)
+}
+
+/// Maps a 1-100 quality onto rav1e's 0-255 quantizer with the same curve as
+/// ravif, so quality values mean the same as in other AVIF tools.
+fn quality_to_quantizer(quality: f32) -> u8 {
+    let q = quality / 100.0;
+    let x = if q >= 0.85 {
+        (1.0 - q) * 3.0
+    } else if q > 0.25 {
+        1.0 - 0.125 - q * 0.5
+    } else {
+        1.0 - q
+    };
+    (x * 255.0).round() as u8
+}
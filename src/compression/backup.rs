@@ -0,0 +1,398 @@
+use crate::compression::template;
+use crate::compression::{BackupMode, CompressionSettings};
+use crate::error::PixelGuardError;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// File name of the manifest written into each batch's backup folder.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Record of an in-place batch, listing where each replaced original was kept.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// When the batch started, as UTC `YYYY-MM-DD_HH-MM-SS`.
+    pub created: String,
+    /// One entry per replaced file.
+    pub entries: Vec<BackupEntry>,
+}
+
+/// An input that was replaced by its compressed version.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupEntry {
+    /// Where the original was, and where restoring puts it back.
+    pub original: PathBuf,
+    /// The compressed file that replaced it.
+    pub output: PathBuf,
+    /// The copy of the original. For trashed backups, the path the copy had
+    /// when it was moved to the trash.
+    pub backup: PathBuf,
+    /// Whether `backup` was moved to the system trash.
+    pub trashed: bool,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of an in-place batch.
+    pub fn load(path: &Path) -> Result<Self, PixelGuardError> {
+        let data = std::fs::read(path).map_err(|e| PixelGuardError::read(path, e))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| PixelGuardError::read(path, io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    /// Writes the manifest to a hidden file next to `path` and renames it
+    /// into place, so an interrupted batch never leaves a truncated manifest.
+    fn save(&self, path: &Path) -> Result<(), PixelGuardError> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| PixelGuardError::write(path, io::Error::other(e)))?;
+        let temp_path = partial_path(path);
+        std::fs::write(&temp_path, data)
+            .and_then(|()| std::fs::rename(&temp_path, path))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&temp_path);
+                PixelGuardError::write(path, e)
+            })
+    }
+}
+
+/// Backups of one in-place batch, shared by its workers.
+pub(crate) struct BackupSession {
+    mode: BackupMode,
+    directory: PathBuf,
+    manifest: Mutex<BackupManifest>,
+    next_backup: AtomicUsize,
+}
+
+impl BackupSession {
+    /// Creates a new backup folder for a batch, or returns `None` if
+    /// `settings` do not keep the originals of an in-place batch.
+    pub(crate) fn start(settings: &CompressionSettings) -> Result<Option<Self>, PixelGuardError> {
+        if !settings.in_place || settings.backup_mode == BackupMode::None {
+            return Ok(None);
+        }
+
+        let root = absolute(Path::new(&settings.backup_directory));
+        std::fs::create_dir_all(&root).map_err(|e| PixelGuardError::write(&root, e))?;
+
+        let created = template::current_timestamp();
+        let mut suffix = 0;
+        let directory = loop {
+            let name = if suffix == 0 {
+                created.clone()
+            } else {
+                format!("{}-{}", created, suffix)
+            };
+            let directory = root.join(name);
+            match std::fs::create_dir(&directory) {
+                Ok(()) => break directory,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(PixelGuardError::write(&directory, e)),
+            }
+        };
+
+        Ok(Some(Self {
+            mode: settings.backup_mode,
+            directory,
+            manifest: Mutex::new(BackupManifest {
+                created,
+                entries: Vec::new(),
+            }),
+            next_backup: AtomicUsize::new(1),
+        }))
+    }
+
+    /// Path of the manifest that [`restore`] reads.
+    pub(crate) fn manifest_path(&self) -> PathBuf {
+        self.directory.join(MANIFEST_FILE_NAME)
+    }
+
+    /// Copies `original` before it is replaced and returns the copy's path.
+    ///
+    /// Trashed backups are copied next to the original first, so the trash
+    /// can put them back on the same drive.
+    pub(crate) fn stash(&self, original: &Path) -> Result<PathBuf, PixelGuardError> {
+        let original = absolute(original);
+        let file_name = original.file_name().unwrap_or_default().to_string_lossy();
+        let backup = match self.mode {
+            BackupMode::Trash => original.with_file_name(format!(".{}.original", file_name)),
+            BackupMode::None | BackupMode::Folder => {
+                let number = self.next_backup.fetch_add(1, Ordering::Relaxed);
+                self.directory.join(format!("{}-{}", number, file_name))
+            }
+        };
+        std::fs::copy(&original, &backup).map_err(|e| PixelGuardError::write(&backup, e))?;
+        Ok(backup)
+    }
+
+    /// Removes the copy of an original that was not replaced after all.
+    pub(crate) fn discard(&self, backup: &Path) {
+        let _ = std::fs::remove_file(backup);
+    }
+
+    /// Records that `original` is about to be replaced by `output`, moving its
+    /// backup to the trash first in trash mode. A backup the trash refuses is
+    /// kept where it is and still restores. Nothing is recorded if the
+    /// manifest cannot be saved.
+    pub(crate) fn commit(
+        &self,
+        original: &Path,
+        output: &Path,
+        backup: &Path,
+    ) -> Result<(), PixelGuardError> {
+        let trashed = self.mode == BackupMode::Trash && trash::delete(backup).is_ok();
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.entries.push(BackupEntry {
+            original: absolute(original),
+            output: absolute(output),
+            backup: backup.to_path_buf(),
+            trashed,
+        });
+        let saved = manifest.save(&self.manifest_path());
+        if saved.is_err() {
+            manifest.entries.pop();
+        }
+        saved
+    }
+
+    /// Drops the entry of `backup` after its original turned out not to be
+    /// replaced, and removes the backup. The backup is kept if the manifest
+    /// cannot be saved, since the entry would still point at it.
+    pub(crate) fn forget(&self, backup: &Path) {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.entries.retain(|entry| entry.backup != backup);
+        if manifest.save(&self.manifest_path()).is_ok() {
+            self.discard(backup);
+        }
+    }
+}
+
+impl Drop for BackupSession {
+    fn drop(&mut self) {
+        // Only succeeds if the batch backed nothing up
+        let _ = std::fs::remove_dir(&self.directory);
+    }
+}
+
+/// Puts back every original listed in the manifest at `manifest_path` and
+/// removes the compressed files that replaced them.
+///
+/// Entries are dropped from the manifest as they are restored, so a restore
+/// that fails part way can simply be run again. Once every file is back the
+/// backup folder is deleted. Returns the number of files restored.
+pub(crate) fn restore(manifest_path: &Path) -> Result<usize, PixelGuardError> {
+    let mut manifest = BackupManifest::load(manifest_path)?;
+    let mut restored = 0;
+    while let Some(entry) = manifest.entries.last() {
+        restore_entry(entry)?;
+        manifest.entries.pop();
+        manifest.save(manifest_path)?;
+        restored += 1;
+    }
+
+    let _ = std::fs::remove_file(manifest_path);
+    if let Some(directory) = manifest_path.parent() {
+        // Only succeeds if nothing else was put in the folder
+        let _ = std::fs::remove_dir(directory);
+    }
+    Ok(restored)
+}
+
+fn restore_entry(entry: &BackupEntry) -> Result<(), PixelGuardError> {
+    if entry.trashed {
+        restore_from_trash(&entry.backup)?;
+    }
+
+    // Copy next to the original and rename, so the original's path never
+    // holds a partial file
+    let temp_path = partial_path(&entry.original);
+    std::fs::copy(&entry.backup, &temp_path)
+        .and_then(|_| std::fs::rename(&temp_path, &entry.original))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            PixelGuardError::write(&entry.original, e)
+        })?;
+
+    if entry.output != entry.original {
+        match std::fs::remove_file(&entry.output) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(PixelGuardError::write(&entry.output, e));
+            }
+            _ => {}
+        }
+    }
+    let _ = std::fs::remove_file(&entry.backup);
+    Ok(())
+}
+
+/// Moves the most recently trashed file that was at `backup` out of the trash.
+#[cfg(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn restore_from_trash(backup: &Path) -> Result<(), PixelGuardError> {
+    // Already restored by an earlier, interrupted attempt
+    if backup.exists() {
+        return Ok(());
+    }
+
+    let trash_error = |e: trash::Error| PixelGuardError::read(backup, io::Error::other(e));
+    let item = trash::os_limited::list()
+        .map_err(trash_error)?
+        .into_iter()
+        .filter(|item| item.original_path() == backup)
+        .max_by_key(|item| item.time_deleted)
+        .ok_or_else(|| {
+            PixelGuardError::read(
+                backup,
+                io::Error::new(io::ErrorKind::NotFound, "backup is no longer in the trash"),
+            )
+        })?;
+    trash::os_limited::restore_all([item]).map_err(trash_error)
+}
+
+#[cfg(not(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn restore_from_trash(backup: &Path) -> Result<(), PixelGuardError> {
+    if backup.exists() {
+        return Ok(());
+    }
+    Err(PixelGuardError::Unsupported(format!(
+        "Restoring from the trash is not supported on this system. Put {} back from the trash and try again",
+        backup.display()
+    )))
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default();
+    path.with_file_name(format!(".{}.partial", file_name.to_string_lossy()))
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{
+        CancellationToken, CompressionEngine, CompressionStatus, OutputFormat,
+    };
+    use crate::file::FileManager;
+    use tempfile::TempDir;
+
+    /// Writes a small gradient PNG to `name` in `directory` and returns its bytes.
+    fn write_png(directory: &TempDir, name: &str) -> (PathBuf, Vec<u8>) {
+        let image =
+            image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 0]));
+        let path = directory.path().join(name);
+        image.save(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        (path, data)
+    }
+
+    /// Compresses `path` in place, keeping the original in a backup folder
+    /// inside `directory`, and returns the batch's manifest path.
+    fn compress_in_place(directory: &TempDir, path: &Path, format: OutputFormat) -> PathBuf {
+        let settings = CompressionSettings {
+            output_format: format,
+            in_place: true,
+            backup_mode: BackupMode::Folder,
+            backup_directory: directory.path().join("backup").display().to_string(),
+            ..CompressionSettings::default()
+        };
+        let file = FileManager::analyze_file(path.to_path_buf()).unwrap();
+        let result =
+            CompressionEngine::compress_single_file(&file, &settings, &CancellationToken::new());
+        assert!(matches!(result.status, CompressionStatus::Success));
+
+        let batch = std::fs::read_dir(directory.path().join("backup"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        batch.path().join(MANIFEST_FILE_NAME)
+    }
+
+    #[test]
+    fn restore_puts_back_an_original_replaced_by_another_format() {
+        let directory = tempfile::tempdir().unwrap();
+        let (original, data) = write_png(&directory, "photo.png");
+        let output = directory.path().join("photo.webp");
+
+        let manifest_path = compress_in_place(&directory, &original, OutputFormat::WebP);
+        assert!(!original.exists());
+        assert!(output.exists());
+        let manifest = BackupManifest::load(&manifest_path).unwrap();
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.entries[0].output, absolute(&output));
+
+        assert_eq!(restore(&manifest_path).unwrap(), 1);
+        assert_eq!(std::fs::read(&original).unwrap(), data);
+        assert!(!output.exists());
+        assert!(!manifest_path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn restore_puts_back_an_original_overwritten_in_place() {
+        let directory = tempfile::tempdir().unwrap();
+        let (original, data) = write_png(&directory, "photo.png");
+
+        let manifest_path = compress_in_place(&directory, &original, OutputFormat::Png);
+        assert_ne!(std::fs::read(&original).unwrap(), data);
+
+        assert_eq!(restore(&manifest_path).unwrap(), 1);
+        assert_eq!(std::fs::read(&original).unwrap(), data);
+    }
+
+    #[test]
+    fn restore_keeps_entries_whose_backup_is_missing() {
+        let directory = tempfile::tempdir().unwrap();
+        let (original, _) = write_png(&directory, "photo.png");
+        let output = directory.path().join("photo.webp");
+
+        let manifest_path = compress_in_place(&directory, &original, OutputFormat::WebP);
+        let manifest = BackupManifest::load(&manifest_path).unwrap();
+        std::fs::remove_file(&manifest.entries[0].backup).unwrap();
+
+        assert!(restore(&manifest_path).is_err());
+        // Nothing is lost: the output stays and the entry can be retried
+        assert!(output.exists());
+        assert_eq!(
+            BackupManifest::load(&manifest_path).unwrap().entries.len(),
+            1
+        );
+    }
+
+    #[test]
+    fn forget_drops_the_entry_and_its_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let (original, _) = write_png(&directory, "photo.png");
+        let settings = CompressionSettings {
+            in_place: true,
+            backup_directory: directory.path().join("backup").display().to_string(),
+            ..CompressionSettings::default()
+        };
+        let session = BackupSession::start(&settings).unwrap().unwrap();
+
+        let backup = session.stash(&original).unwrap();
+        session.commit(&original, &original, &backup).unwrap();
+        session.forget(&backup);
+
+        assert!(!backup.exists());
+        let manifest = BackupManifest::load(&session.manifest_path()).unwrap();
+        assert!(manifest.entries.is_empty());
+    }
+}
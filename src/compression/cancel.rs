@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// How often long running encoder steps check for cancellation.
+pub(crate) const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shared handle used to cancel, pause or resume a running batch.
+///
+/// Clones refer to the same job, so the UI can keep one copy while the
+/// engine checks another between files.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    state_changed: Condvar,
+}
+
+impl CancellationToken {
+    /// Creates a token that is neither cancelled nor paused.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation. Files that have not finished are skipped.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        // Wake paused workers so they can observe the cancellation
+        let _paused = self.inner.paused.lock().unwrap();
+        self.inner.state_changed.notify_all();
+    }
+
+    /// Returns `true` once [`cancel`](Self::cancel) has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Stops workers from starting new files until [`resume`](Self::resume) is called.
+    pub fn pause(&self) {
+        *self.inner.paused.lock().unwrap() = true;
+    }
+
+    /// Lets paused workers continue.
+    pub fn resume(&self) {
+        *self.inner.paused.lock().unwrap() = false;
+        self.inner.state_changed.notify_all();
+    }
+
+    /// Returns `true` while the job is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.inner.paused.lock().unwrap()
+    }
+
+    /// Blocks while the job is paused. Returns `false` if it was cancelled.
+    pub fn wait_while_paused(&self) -> bool {
+        let mut paused = self.inner.paused.lock().unwrap();
+        while *paused && !self.is_cancelled() {
+            paused = self.inner.state_changed.wait(paused).unwrap();
+        }
+        !self.is_cancelled()
+    }
+}
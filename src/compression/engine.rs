@@ -1,151 +1,1166 @@
-use crate::compression::{CompressionResult, CompressionSettings, OutputFormat};
-use crate::file::ImageFile;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc;
-use std::time::Instant;
+use crate::compression::animation::{self, Animation};
+use crate::compression::backup::{self, BackupSession};
+use crate::compression::cancel::CANCEL_POLL_INTERVAL;
+use crate::compression::metadata::Metadata;
+use crate::compression::{
+    CancellationToken, CompressionResult, CompressionSettings, ConflictPolicy, MetadataKind,
+    OutputFormat, PngDeflater, PngFilter, QualitySearch, ResizeMode, ResizeSettings, WebPMode,
+};
+use crate::compression::{QualityMetrics, avif, jxl, metrics, resize, template, variants};
+use crate::error::PixelGuardError;
+use crate::file::{FileManager, ImageFile, ImageFormat};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::num::NonZeroU8;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::time::{Duration, Instant};
 
+/// Wait before retrying a transient I/O error. Each further retry waits longer.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Numbered names tried by [`ConflictPolicy::Rename`] before giving up.
+const MAX_RENAME_ATTEMPTS: u32 = 10_000;
+
+/// Images are not downscaled below this width and height to meet a size limit.
+const MIN_FIT_DIMENSION: u32 = 16;
+
+/// An image encoded in memory, waiting to be written.
+struct EncodedImage {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Quality picked by a size or visual quality search, `None` if the
+    /// configured quality was used.
+    quality: Option<f32>,
+    /// Metrics already measured by a visual quality search.
+    metrics: Option<QualityMetrics>,
+    /// Format picked by [`OutputFormat::Auto`], `None` if the configured format was used.
+    format: Option<OutputFormat>,
+    /// Metadata the policy removed from the input.
+    metadata_removed: Vec<MetadataKind>,
+}
+
+impl EncodedImage {
+    fn new(data: Vec<u8>, width: u32, height: u32) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            quality: None,
+            metrics: None,
+            format: None,
+            metadata_removed: Vec::new(),
+        }
+    }
+}
+
+/// Converts and optimizes images according to [`CompressionSettings`].
 pub struct CompressionEngine;
 
 impl CompressionEngine {
+    /// Compresses `files` on a pool of worker threads, reporting progress on `progress_sender`.
+    ///
+    /// Creates the output directory if needed and returns one result per file,
+    /// or one per variant when
+    /// [`generate_variants`](CompressionSettings::generate_variants) is on,
+    /// in the same order as `files`. In-place batches share one backup folder
+    /// and manifest. Blocks until the batch is done, so it is
+    /// intended to run on a background thread while the caller drains the channel.
+    /// Files that have not finished when `cancel_token` is cancelled are
+    /// reported as [`CompressionStatus::Skipped`](crate::compression::CompressionStatus::Skipped).
     pub fn compress_files(
         files: Vec<ImageFile>,
         settings: CompressionSettings,
         progress_sender: mpsc::Sender<CompressionProgress>,
+        cancel_token: &CancellationToken,
     ) -> Vec<CompressionResult> {
-        let mut results = Vec::new();
         let total_files = files.len();
 
         // Ensure output directory exists
-        if let Err(e) = std::fs::create_dir_all(&settings.output_directory) {
-            let _ = progress_sender.send(CompressionProgress::Error(format!(
-                "Failed to create output directory: {}",
-                e
+        if !settings.in_place
+            && let Err(e) = std::fs::create_dir_all(&settings.output_directory)
+        {
+            let _ = progress_sender.send(CompressionProgress::Error(PixelGuardError::write(
+                Path::new(&settings.output_directory),
+                e,
             )));
-            return results;
+            return Vec::new();
         }
 
-        for (index, file) in files.into_iter().enumerate() {
-            let _ = progress_sender.send(CompressionProgress::Processing {
-                current: index + 1,
-                total: total_files,
-                filename: file.name.clone(),
-            });
+        let backups = match BackupSession::start(&settings) {
+            Ok(backups) => backups,
+            Err(error) => {
+                let _ = progress_sender.send(CompressionProgress::Error(error));
+                return Vec::new();
+            }
+        };
+
+        let worker_count = settings.worker_count().min(total_files).max(1);
+        let next_index = AtomicUsize::new(0);
+        // Held while sending progress so the reported fraction never goes backwards
+        let completed = Mutex::new(0);
+
+        let indexed_results: Vec<(usize, Vec<CompressionResult>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..worker_count)
+                .map(|_| {
+                    let progress_sender = progress_sender.clone();
+                    let files = &files;
+                    let settings = &settings;
+                    let backups = backups.as_ref();
+                    let next_index = &next_index;
+                    let completed = &completed;
+
+                    scope.spawn(move || {
+                        let mut results = Vec::new();
+
+                        loop {
+                            if !cancel_token.wait_while_paused() {
+                                break;
+                            }
+
+                            let index = next_index.fetch_add(1, Ordering::Relaxed);
+                            let Some(file) = files.get(index) else {
+                                break;
+                            };
+
+                            let _ = progress_sender.send(CompressionProgress::Processing {
+                                current: index + 1,
+                                total: total_files,
+                                filename: file.name.clone(),
+                            });
+
+                            // A panic on one malformed image must not take the batch down
+                            let file_results = panic::catch_unwind(AssertUnwindSafe(|| {
+                                Self::compress_file_variants(file, settings, backups, cancel_token)
+                            }))
+                            .unwrap_or_else(|payload| {
+                                vec![CompressionResult::failed(
+                                    file.path.clone(),
+                                    PixelGuardError::Crashed(panic_message(payload.as_ref())),
+                                )]
+                            });
+                            results.push((index, file_results));
+
+                            let mut completed = completed.lock().unwrap();
+                            *completed += 1;
+                            let progress = *completed as f32 / total_files as f32;
+                            let _ = progress_sender.send(CompressionProgress::Progress(progress));
+                        }
 
-            let result = Self::compress_single_file(&file, &settings);
-            results.push(result);
+                        results
+                    })
+                })
+                .collect();
 
-            let progress = (index + 1) as f32 / total_files as f32;
-            let _ = progress_sender.send(CompressionProgress::Progress(progress));
+            // Files of a worker that died anyway are reported below
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_default())
+                .collect()
+        });
+
+        let mut results = vec![None; total_files];
+        for (index, result) in indexed_results {
+            results[index] = Some(result);
         }
 
         let _ = progress_sender.send(CompressionProgress::Complete);
+        results
+            .into_iter()
+            .zip(&files)
+            .flat_map(|(results, file)| {
+                results.unwrap_or_else(|| {
+                    if cancel_token.is_cancelled() {
+                        vec![Self::cancelled(file)]
+                    } else {
+                        let error = PixelGuardError::Crashed("worker thread died".to_string());
+                        vec![CompressionResult::failed(file.path.clone(), error)]
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Compresses a single file into `settings.output_directory`, which must already exist.
+    /// Folders mirrored from `settings.input_root` are created as needed.
+    ///
+    /// The image is encoded in memory and only written once it is complete, so
+    /// a cancelled or failed file never leaves a partial output behind. In
+    /// place, the file gets a backup folder of its own.
+    pub fn compress_single_file(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        cancel_token: &CancellationToken,
+    ) -> CompressionResult {
+        match BackupSession::start(settings) {
+            Ok(backups) => Self::compress_file(file, settings, backups.as_ref(), cancel_token),
+            Err(error) => CompressionResult::failed(file.path.clone(), error),
+        }
+    }
+
+    /// Puts back the originals of an in-place batch from the manifest at
+    /// `manifest_path` and removes the compressed files that replaced them.
+    ///
+    /// Entries are dropped from the manifest as they are restored, so a
+    /// restore that fails part way can be run again. Once every file is back
+    /// the batch's backup folder is deleted. Returns the number of files restored.
+    pub fn restore_backup(manifest_path: &Path) -> Result<usize, PixelGuardError> {
+        backup::restore(manifest_path)
+    }
+
+    /// Compresses `file` into each of `settings.variants`, or into the single
+    /// configured output if variants are off. Variants that would come out at
+    /// the same size and format as an earlier one are skipped.
+    fn compress_file_variants(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        backups: Option<&BackupSession>,
+        cancel_token: &CancellationToken,
+    ) -> Vec<CompressionResult> {
+        if !settings.generate_variants {
+            return vec![Self::compress_file(file, settings, backups, cancel_token)];
+        }
+
+        let source_dimensions = Self::upright_dimensions(&file.path).ok();
+        let mut written = Vec::new();
+        let mut results = Vec::new();
+        for variant in &settings.variants {
+            let variant_settings = settings.for_variant(variant);
+            if let Some((width, height)) = source_dimensions {
+                let dimensions = if variant_settings.resize_images {
+                    resize::resized_size(&variant_settings.resize, width, height)
+                } else {
+                    (width, height)
+                };
+                if written.contains(&(&variant.format, dimensions)) {
+                    results.push(CompressionResult::skipped(
+                        file.path.clone(),
+                        format!(
+                            "The {}px {} variant would repeat an earlier one, the image is smaller",
+                            variant.width,
+                            variant.format.name().to_uppercase()
+                        ),
+                    ));
+                    continue;
+                }
+                written.push((&variant.format, dimensions));
+            }
+            results.push(Self::compress_file(
+                file,
+                &variant_settings,
+                backups,
+                cancel_token,
+            ));
+        }
+
+        if let Err(error) = variants::write_listings(&file.path, settings, &results) {
+            results.push(CompressionResult::failed(file.path.clone(), error));
+        }
         results
     }
 
-    fn compress_single_file(file: &ImageFile, settings: &CompressionSettings) -> CompressionResult {
+    fn compress_file(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        backups: Option<&BackupSession>,
+        cancel_token: &CancellationToken,
+    ) -> CompressionResult {
         let start_time = Instant::now();
 
-        let output_path = Self::generate_output_path(file, settings);
+        let encoded = match Self::perform_compression_with_retries(file, settings, cancel_token) {
+            Ok(encoded) => encoded,
+            Err(_) if cancel_token.is_cancelled() => return Self::cancelled(file),
+            Err(error) => return CompressionResult::failed(file.path.clone(), error),
+        };
 
-        match Self::perform_compression(file, &output_path, settings) {
-            Ok(compressed_size) => {
-                let processing_time = start_time.elapsed();
-                CompressionResult::new(
+        if cancel_token.is_cancelled() {
+            return Self::cancelled(file);
+        }
+
+        if let Some(reason) = Self::insufficient_savings(file, settings, &encoded) {
+            return Self::keep_original(file, settings, &encoded, reason, cancel_token);
+        }
+
+        let metrics = if settings.measure_quality {
+            encoded
+                .metrics
+                .or_else(|| Self::measure_quality(file, settings, &encoded))
+        } else {
+            None
+        };
+
+        let output_path = match Self::generate_output_path(file, settings, &encoded) {
+            Ok(output_path) => output_path,
+            Err(error) => return CompressionResult::failed(file.path.clone(), error),
+        };
+
+        let backup = match backups.map(|backups| backups.stash(&file.path)).transpose() {
+            Ok(backup) => backup,
+            Err(error) => return CompressionResult::failed(file.path.clone(), error),
+        };
+
+        let policy = match settings.conflict_policy {
+            // Replacing the input is the point of in-place mode
+            _ if settings.in_place && output_path == file.path => ConflictPolicy::Overwrite,
+            // Any other file would be lost for good, as it has no backup and
+            // restoring removes the output
+            ConflictPolicy::Overwrite if settings.in_place => ConflictPolicy::Rename,
+            policy => policy,
+        };
+
+        let claimed = Self::with_retries(settings, cancel_token, || {
+            Self::claim_output(&output_path, policy)
+        });
+        let output_path = match claimed {
+            Ok(output_path) => output_path,
+            Err(error) => {
+                if let (Some(backups), Some(backup)) = (backups, &backup) {
+                    backups.discard(backup);
+                }
+                return match error {
+                    PixelGuardError::OutputExists { path } if policy == ConflictPolicy::Skip => {
+                        CompressionResult::skipped(
+                            file.path.clone(),
+                            format!("{} already exists", path.display()),
+                        )
+                    }
+                    error => CompressionResult::failed(file.path.clone(), error),
+                };
+            }
+        };
+        let release_claim = || {
+            if policy != ConflictPolicy::Overwrite {
+                let _ = std::fs::remove_file(&output_path);
+            }
+        };
+
+        // The backup is recorded before the original is touched, so restoring
+        // always knows about every replaced file
+        if let (Some(backups), Some(backup)) = (backups, &backup)
+            && let Err(error) = backups.commit(&file.path, &output_path, backup)
+        {
+            backups.discard(backup);
+            release_claim();
+            return CompressionResult::failed(file.path.clone(), error);
+        }
+
+        let written = Self::with_retries(settings, cancel_token, || {
+            Self::write_claimed(&output_path, &encoded.data)
+        });
+        if let Err(error) = written {
+            if let (Some(backups), Some(backup)) = (backups, &backup) {
+                backups.forget(backup);
+            }
+            release_claim();
+            return CompressionResult::failed(file.path.clone(), error);
+        }
+
+        if settings.in_place
+            && output_path != file.path
+            && let Err(error) = std::fs::remove_file(&file.path)
+        {
+            return CompressionResult::failed(
+                file.path.clone(),
+                PixelGuardError::write(&file.path, error),
+            );
+        }
+
+        let processing_time = start_time.elapsed();
+        let mut result = CompressionResult::new(
+            file.path.clone(),
+            output_path,
+            file.size,
+            encoded.data.len() as u64,
+            processing_time,
+        );
+        result.output_format = Some(
+            encoded
+                .format
+                .unwrap_or_else(|| settings.output_format.clone()),
+        );
+        result.dimensions = Some((encoded.width, encoded.height));
+        result.quality = encoded.quality;
+        result.quality_search = encoded.quality.map(|_| {
+            if settings.match_visual_quality {
+                QualitySearch::VisualTarget
+            } else {
+                QualitySearch::SizeLimit
+            }
+        });
+        result.metrics = metrics;
+        result.metadata_removed = encoded.metadata_removed;
+        result.backup_manifest = backups.map(BackupSession::manifest_path);
+        result
+    }
+
+    /// Compares the first frame of `file`, resized as configured, with the
+    /// decoded output, or returns `None` if the output format cannot be
+    /// decoded here.
+    fn measure_quality(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        encoded: &EncodedImage,
+    ) -> Option<QualityMetrics> {
+        let output = image::load_from_memory(&encoded.data).ok()?.to_rgba8();
+        let input = Self::load_resized_image(file, settings).ok()?.to_rgba8();
+        metrics::measure(&input, &output)
+    }
+
+    /// Explains why `encoded` is not worth writing, or returns `None` if it
+    /// saves enough.
+    fn insufficient_savings(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        encoded: &EncodedImage,
+    ) -> Option<String> {
+        if !settings.require_savings || file.size == 0 {
+            return None;
+        }
+        // Copying the original would bring back the metadata the policy removed
+        let copies_original = settings.copy_original_on_skip && !settings.in_place;
+        if copies_original && !encoded.metadata_removed.is_empty() {
+            return None;
+        }
+
+        let compressed_size = encoded.data.len() as u64;
+        let savings = 100.0 * (1.0 - compressed_size as f64 / file.size as f64);
+        if compressed_size < file.size && savings >= f64::from(settings.min_savings_percent) {
+            None
+        } else if compressed_size > file.size {
+            Some(format!("output would be {:.1}% larger", -savings))
+        } else if compressed_size == file.size {
+            Some("output would not be smaller".to_string())
+        } else {
+            Some(format!(
+                "output would only be {:.1}% smaller, less than {}%",
+                savings, settings.min_savings_percent
+            ))
+        }
+    }
+
+    /// Skips `file`, copying it into the output directory unchanged if
+    /// `settings` ask for it. In place there is nothing to copy.
+    fn keep_original(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        encoded: &EncodedImage,
+        reason: String,
+        cancel_token: &CancellationToken,
+    ) -> CompressionResult {
+        if !settings.copy_original_on_skip || settings.in_place {
+            return CompressionResult::skipped(file.path.clone(), reason);
+        }
+
+        let extension = file.path.extension().unwrap_or_default();
+        let copied = Self::with_retries(settings, cancel_token, || {
+            let data =
+                std::fs::read(&file.path).map_err(|e| PixelGuardError::read(&file.path, e))?;
+            let original = EncodedImage::new(data, encoded.width, encoded.height);
+            let output_path =
+                Self::generate_output_path(file, settings, &original)?.with_extension(extension);
+            Self::write_output(&output_path, &original.data, settings.conflict_policy)
+        });
+
+        match copied {
+            Ok(output_path) => {
+                let mut result = CompressionResult::skipped(
                     file.path.clone(),
-                    output_path,
-                    file.size,
-                    compressed_size,
-                    processing_time,
+                    format!("{}, copied the original", reason),
+                );
+                result.output_path = output_path;
+                result.original_size = file.size;
+                result.compressed_size = file.size;
+                result
+            }
+            Err(PixelGuardError::OutputExists { path })
+                if settings.conflict_policy == ConflictPolicy::Skip =>
+            {
+                CompressionResult::skipped(
+                    file.path.clone(),
+                    format!("{}, and {} already exists", reason, path.display()),
                 )
             }
             Err(error) => CompressionResult::failed(file.path.clone(), error),
         }
     }
 
+    fn cancelled(file: &ImageFile) -> CompressionResult {
+        CompressionResult::skipped(file.path.clone(), "cancelled".to_string())
+    }
+
+    fn perform_compression_with_retries(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        Self::with_retries(settings, cancel_token, || {
+            Self::perform_compression(file, settings, cancel_token)
+        })
+    }
+
+    /// Runs `operation` again after transient I/O errors, up to
+    /// `settings.io_retries` more times with a growing delay.
+    fn with_retries<T>(
+        settings: &CompressionSettings,
+        cancel_token: &CancellationToken,
+        mut operation: impl FnMut() -> Result<T, PixelGuardError>,
+    ) -> Result<T, PixelGuardError> {
+        let mut attempt = 0;
+        loop {
+            match operation() {
+                Err(error)
+                    if error.is_transient()
+                        && attempt < settings.io_retries
+                        && !cancel_token.is_cancelled() =>
+                {
+                    attempt += 1;
+                    std::thread::sleep(RETRY_DELAY * attempt);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Width and height of the image at `path` as it is displayed, with
+    /// sides swapped if its EXIF orientation turns it by 90 degrees. Only the
+    /// header is read.
+    pub fn upright_dimensions(path: &Path) -> Result<(u32, u32), PixelGuardError> {
+        let mut decoder = Self::decoder(path)?;
+        let (width, height) = decoder.dimensions();
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        Ok(match orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (height, width),
+            _ => (width, height),
+        })
+    }
+
+    fn decoder(path: &Path) -> Result<impl ImageDecoder, PixelGuardError> {
+        ImageReader::open(path)
+            .map_err(|e| PixelGuardError::read(path, e))?
+            .into_decoder()
+            .map_err(|e| PixelGuardError::decode(path, e))
+    }
+
+    /// Decodes `file` and rotates or flips it upright as its EXIF orientation
+    /// says, since the encoders do not carry the tag over.
+    fn load_image(file: &ImageFile) -> Result<image::DynamicImage, PixelGuardError> {
+        let mut decoder = Self::decoder(&file.path)?;
+        // An unreadable EXIF block should not keep the pixels from being compressed
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        let mut img = DynamicImage::from_decoder(decoder)
+            .map_err(|e| PixelGuardError::decode(&file.path, e))?;
+        img.apply_orientation(orientation);
+        Ok(img)
+    }
+
+    /// Decodes `file` and applies the resize stage if it is enabled.
+    fn load_resized_image(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+    ) -> Result<image::DynamicImage, PixelGuardError> {
+        let img = Self::load_image(file)?;
+        Ok(if settings.resize_images {
+            resize::resize(img, &settings.resize)
+        } else {
+            img
+        })
+    }
+
     fn perform_compression(
         file: &ImageFile,
-        output_path: &Path,
         settings: &CompressionSettings,
-    ) -> Result<u64, String> {
-        match settings.output_format {
-            OutputFormat::Png => Self::compress_to_png(file, output_path, settings),
-            OutputFormat::WebP => Self::compress_to_webp(file, output_path, settings),
-            OutputFormat::Jpeg => Self::compress_to_jpeg(file, output_path, settings),
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        if settings.output_format == OutputFormat::Auto {
+            return Self::compress_to_smallest(file, settings, cancel_token);
         }
+
+        let (metadata, metadata_removed) = Metadata::read(file)?.filter(&settings.metadata);
+        let mut encoded =
+            Self::encode_file(file, settings, metadata, &metadata_removed, cancel_token)?;
+        encoded.metadata_removed = metadata_removed;
+        Ok(encoded)
     }
 
-    fn compress_to_png(
+    /// Encodes `file` in the configured format, carrying `metadata` over.
+    /// `metadata_removed` lists what the policy removed.
+    fn encode_file(
         file: &ImageFile,
-        output_path: &Path,
         settings: &CompressionSettings,
-    ) -> Result<u64, String> {
-        // Load and convert image to PNG
-        let img = image::open(&file.path).map_err(|e| format!("Failed to load image: {}", e))?;
+        metadata: Metadata,
+        metadata_removed: &[MetadataKind],
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        if settings.output_format.supports_animation()
+            && let Some(mut animation) = animation::decode(file)?
+        {
+            if settings.resize_images {
+                animation.resize(&settings.resize);
+            }
+            return Self::encode_animation_to_fit(&animation, settings, &metadata, cancel_token);
+        }
 
-        // Save as PNG first
-        let temp_path = output_path.with_extension("temp.png");
-        img.save(&temp_path)
-            .map_err(|e| format!("Failed to save PNG: {}", e))?;
+        if Self::transcodes_jpeg(file, settings) {
+            // Repackaging keeps the JPEG's own data, so it is never decoded. Only
+            // metadata the policy removes is taken out first
+            let (width, height) = Self::upright_dimensions(&file.path)?;
+            let jpeg =
+                std::fs::read(&file.path).map_err(|e| PixelGuardError::read(&file.path, e))?;
+            let jpeg = metadata.replace_in_jpeg(jpeg, metadata_removed)?;
+            let data = jxl::transcode_jpeg(&jpeg, &settings.jxl, cancel_token)?;
+            if !Self::exceeds_size_limit(settings, &data) {
+                return Ok(EncodedImage::new(data, width, height));
+            }
+            if settings.lossless_only {
+                return Err(PixelGuardError::Unsupported(format!(
+                    "Cannot fit the repackaged JPEG in {} without a lossy encode",
+                    FileManager::format_file_size(settings.max_file_size)
+                )));
+            }
+            // Too large to repackage, so the pixels are re-encoded to fit below
+        }
 
-        // Optimize with oxipng
-        let mut options = oxipng::Options::default();
-        options.optimize_alpha = true;
-        options.strip = if settings.preserve_metadata {
-            oxipng::StripChunks::None
-        } else {
-            oxipng::StripChunks::Safe
+        let img = Self::load_resized_image(file, settings)?;
+        let metadata = &metadata.upright();
+        if settings.match_visual_quality {
+            return Self::encode_to_visual_target(&img, settings, metadata, cancel_token);
+        }
+        if settings.limit_file_size {
+            return Self::encode_to_fit(&img, settings, metadata, cancel_token);
+        }
+
+        Ok(EncodedImage::new(
+            Self::encode_still(&img, settings, metadata, cancel_token)?,
+            img.width(),
+            img.height(),
+        ))
+    }
+
+    /// Compresses `file` into every candidate format of [`OutputFormat::Auto`]
+    /// and keeps the smallest output. Candidates that fail, e.g. because
+    /// `cjxl` is not installed, are left out.
+    fn compress_to_smallest(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        let animated = animation::decode(file)?.is_some();
+        let repackages_jpeg = file.format == ImageFormat::Jpeg && settings.jxl.lossless_jpeg;
+        let mut smallest: Option<EncodedImage> = None;
+        let mut first_error = None;
+
+        for format in Self::auto_candidates(settings, animated, repackages_jpeg) {
+            if cancel_token.is_cancelled() {
+                return Err(PixelGuardError::Cancelled);
+            }
+
+            let candidate = Self::with_format(settings, format.clone());
+            match Self::perform_compression(file, &candidate, cancel_token) {
+                Ok(encoded) => {
+                    if smallest
+                        .as_ref()
+                        .is_none_or(|smallest| encoded.data.len() < smallest.data.len())
+                    {
+                        smallest = Some(EncodedImage {
+                            format: Some(format),
+                            ..encoded
+                        });
+                    }
+                }
+                Err(PixelGuardError::Cancelled) => return Err(PixelGuardError::Cancelled),
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        smallest.ok_or_else(|| {
+            first_error.unwrap_or_else(|| {
+                PixelGuardError::Unsupported("No output format can hold this image".to_string())
+            })
+        })
+    }
+
+    /// Formats [`OutputFormat::Auto`] tries for a file. Animated inputs only
+    /// try formats that keep every frame, and
+    /// [`lossless_only`](CompressionSettings::lossless_only) leaves out
+    /// formats that lose detail. Under a size limit, lossless JPEG XL is only
+    /// tried for JPEGs it can repackage, since shrinking anything else to
+    /// fit takes a lossy encode.
+    fn auto_candidates(
+        settings: &CompressionSettings,
+        animated: bool,
+        repackages_jpeg: bool,
+    ) -> Vec<OutputFormat> {
+        let lossy = !settings.lossless_only;
+        // Outputs are decoded to measure them, which AVIF and JPEG XL do not support yet
+        let measurable = !settings.match_visual_quality;
+        let jxl_fits = lossy || !settings.limit_file_size || repackages_jpeg;
+        [
+            (OutputFormat::Png, true),
+            (OutputFormat::WebP, true),
+            (OutputFormat::Jpeg, lossy),
+            (OutputFormat::Avif, lossy && measurable),
+            (OutputFormat::JpegXl, measurable && jxl_fits),
+        ]
+        .into_iter()
+        .filter(|(format, allowed)| *allowed && (!animated || format.supports_animation()))
+        .map(|(format, _)| format)
+        .collect()
+    }
+
+    /// Returns `settings` writing `format`, made lossless if
+    /// [`lossless_only`](CompressionSettings::lossless_only) is on.
+    fn with_format(settings: &CompressionSettings, format: OutputFormat) -> CompressionSettings {
+        let mut settings = settings.clone();
+        settings.output_format = format;
+        if settings.lossless_only {
+            settings.webp.mode = WebPMode::Lossless;
+            settings.jxl_quality = 100.0;
+        }
+        settings
+    }
+
+    fn encode_still(
+        img: &DynamicImage,
+        settings: &CompressionSettings,
+        metadata: &Metadata,
+        cancel_token: &CancellationToken,
+    ) -> Result<Vec<u8>, PixelGuardError> {
+        Ok(match settings.output_format {
+            OutputFormat::Png => {
+                metadata.embed_png(Self::compress_to_png(img, settings, cancel_token)?)?
+            }
+            OutputFormat::WebP => metadata.embed_webp(Self::compress_to_webp(img, settings)?)?,
+            OutputFormat::Jpeg => metadata.embed_jpeg(Self::compress_to_jpeg(img, settings)?)?,
+            OutputFormat::Avif => avif::encode(
+                &img.to_rgba8(),
+                settings.avif_quality,
+                &settings.avif,
+                metadata.exif.as_deref(),
+            )?,
+            OutputFormat::JpegXl => jxl::encode(
+                img,
+                settings.jxl_quality,
+                &settings.jxl,
+                metadata,
+                cancel_token,
+            )?,
+            // GIF has no standard place for EXIF or ICC data
+            OutputFormat::Gif => animation::encode_gif(&Animation::still(img.to_rgba8()))?,
+            OutputFormat::Auto => {
+                return Err(PixelGuardError::Unsupported(
+                    "Auto must be resolved to a format before encoding".to_string(),
+                ));
+            }
+        })
+    }
+
+    fn exceeds_size_limit(settings: &CompressionSettings, data: &[u8]) -> bool {
+        settings.limit_file_size && data.len() as u64 > settings.max_file_size
+    }
+
+    fn encode_animation(
+        animation: &Animation,
+        settings: &CompressionSettings,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, PixelGuardError> {
+        Ok(match settings.output_format {
+            OutputFormat::WebP => metadata.embed_webp(animation::encode_webp(
+                animation,
+                &Self::webp_config(settings)?,
+            )?)?,
+            OutputFormat::Gif => animation::encode_gif(animation)?,
+            _ => metadata.embed_png(animation::encode_apng(animation)?)?,
+        })
+    }
+
+    /// Encodes `animation`, downscaling every frame when the output does not
+    /// fit in `settings.max_file_size`. Each attempt scales the original
+    /// frames, so resampling errors do not add up.
+    fn encode_animation_to_fit(
+        animation: &Animation,
+        settings: &CompressionSettings,
+        metadata: &Metadata,
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        let mut scaled = Cow::Borrowed(animation);
+        loop {
+            let data = Self::encode_animation(&scaled, settings, metadata)?;
+            let (width, height) = scaled.dimensions();
+            if !Self::exceeds_size_limit(settings, &data) {
+                return Ok(EncodedImage::new(data, width, height));
+            }
+            if cancel_token.is_cancelled() {
+                return Err(PixelGuardError::Cancelled);
+            }
+
+            let (width, height) = Self::fit_dimensions(settings, width, height, data.len())?;
+            let mut resized = animation.clone();
+            resized.resize(&ResizeSettings {
+                max_width: width,
+                max_height: height,
+                mode: ResizeMode::Exact,
+                never_upscale: false,
+                filter: settings.resize.filter,
+            });
+            scaled = Cow::Owned(resized);
+        }
+    }
+
+    /// Returns the size to scale a `width` × `height` image to when its
+    /// smallest output of `smallest_size` bytes is too large, or an error if
+    /// downscaling is off or would go below [`MIN_FIT_DIMENSION`].
+    fn fit_dimensions(
+        settings: &CompressionSettings,
+        width: u32,
+        height: u32,
+        smallest_size: usize,
+    ) -> Result<(u32, u32), PixelGuardError> {
+        // Size roughly follows the pixel count, so scale both sides by the
+        // square root of the overshoot, with a margin
+        let scale = (settings.max_file_size as f64 / smallest_size as f64).sqrt() * 0.9;
+        let width = (f64::from(width) * scale) as u32;
+        let height = (f64::from(height) * scale) as u32;
+        if !settings.downscale_to_fit || width < MIN_FIT_DIMENSION || height < MIN_FIT_DIMENSION {
+            return Err(PixelGuardError::Unsupported(format!(
+                "Cannot fit the image in {}, the smallest output was {}",
+                FileManager::format_file_size(settings.max_file_size),
+                FileManager::format_file_size(smallest_size as u64)
+            )));
+        }
+        Ok((width, height))
+    }
+
+    /// Encodes `img` at the highest quality that fits in `settings.max_file_size`,
+    /// downscaling it when even the lowest quality is too large. Formats
+    /// without a quality setting are only downscaled.
+    fn encode_to_fit(
+        img: &DynamicImage,
+        settings: &CompressionSettings,
+        metadata: &Metadata,
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        let original = img;
+        let mut img = Cow::Borrowed(img);
+        loop {
+            let smallest_size = match Self::quality_range(settings) {
+                Some((min_quality, max_quality)) => {
+                    // Binary search, assuming size grows with quality
+                    let (mut low, mut high) = (i32::from(min_quality), i32::from(max_quality));
+                    let mut best = None;
+                    let mut smallest_size = 0;
+                    while low <= high {
+                        if cancel_token.is_cancelled() {
+                            return Err(PixelGuardError::Cancelled);
+                        }
+                        let quality = ((low + high) / 2) as f32;
+                        let data = Self::encode_still(
+                            &img,
+                            &Self::with_quality(settings, quality),
+                            metadata,
+                            cancel_token,
+                        )?;
+                        if Self::exceeds_size_limit(settings, &data) {
+                            smallest_size = data.len();
+                            high = quality as i32 - 1;
+                        } else {
+                            best = Some((quality, data));
+                            low = quality as i32 + 1;
+                        }
+                    }
+                    if let Some((quality, data)) = best {
+                        return Ok(EncodedImage {
+                            quality: Some(quality),
+                            ..EncodedImage::new(data, img.width(), img.height())
+                        });
+                    }
+                    // The search ended on a failed encode at the minimum quality
+                    smallest_size
+                }
+                None => {
+                    let data = Self::encode_still(&img, settings, metadata, cancel_token)?;
+                    if !Self::exceeds_size_limit(settings, &data) {
+                        return Ok(EncodedImage::new(data, img.width(), img.height()));
+                    }
+                    data.len()
+                }
+            };
+
+            let (width, height) =
+                Self::fit_dimensions(settings, img.width(), img.height(), smallest_size)?;
+            // Always scale the original, so resampling errors do not add up
+            img = Cow::Owned(original.resize_exact(
+                width,
+                height,
+                resize::filter_type(settings.resize.filter),
+            ));
+        }
+    }
+
+    /// Encodes `img` at the lowest quality whose output still reaches
+    /// `settings.visual_target`, or at the highest quality if none does or the
+    /// target cannot be measured. Formats without a quality setting are
+    /// encoded as configured.
+    fn encode_to_visual_target(
+        img: &DynamicImage,
+        settings: &CompressionSettings,
+        metadata: &Metadata,
+        cancel_token: &CancellationToken,
+    ) -> Result<EncodedImage, PixelGuardError> {
+        let Some((min_quality, max_quality)) = Self::quality_range(settings) else {
+            return Ok(EncodedImage::new(
+                Self::encode_still(img, settings, metadata, cancel_token)?,
+                img.width(),
+                img.height(),
+            ));
         };
 
-        match oxipng::optimize(
-            &oxipng::InFile::Path(temp_path.clone()),
-            &oxipng::OutFile::Path {
-                path: Some(output_path.to_path_buf()),
-                preserve_attrs: false,
-            },
-            &options,
-        ) {
-            Ok(_) => {
-                let _ = std::fs::remove_file(temp_path);
-                let metadata = std::fs::metadata(output_path)
-                    .map_err(|e| format!("Failed to read output size: {}", e))?;
-                Ok(metadata.len())
+        let reference = img.to_rgba8();
+        let mut best = None;
+        // Binary search, assuming visual quality grows with quality
+        let (mut low, mut high) = (i32::from(min_quality), i32::from(max_quality));
+        while low <= high {
+            if cancel_token.is_cancelled() {
+                return Err(PixelGuardError::Cancelled);
             }
-            Err(e) => {
-                let _ = std::fs::remove_file(temp_path);
-                Err(format!("PNG optimization failed: {}", e))
+            let quality = ((low + high) / 2) as f32;
+            let data = Self::encode_still(
+                img,
+                &Self::with_quality(settings, quality),
+                metadata,
+                cancel_token,
+            )?;
+            let decoded = image::load_from_memory(&data).map_err(|_| {
+                PixelGuardError::Unsupported(format!(
+                    "Visual quality targets need an output format PixelGuard can decode, not {}",
+                    settings.output_format.name()
+                ))
+            })?;
+            let metrics = metrics::measure(&reference, &decoded.to_rgba8());
+            if metrics.is_some_and(|metrics| settings.visual_target.is_met_by(&metrics)) {
+                best = Some((quality, data, metrics));
+                high = quality as i32 - 1;
+            } else {
+                low = quality as i32 + 1;
             }
         }
+
+        // Nothing reaches the target, so keep as much quality as possible
+        let (quality, data, metrics) = match best {
+            Some(best) => best,
+            None => {
+                let quality = f32::from(max_quality);
+                let data = Self::encode_still(
+                    img,
+                    &Self::with_quality(settings, quality),
+                    metadata,
+                    cancel_token,
+                )?;
+                (quality, data, None)
+            }
+        };
+        Ok(EncodedImage {
+            quality: Some(quality),
+            metrics,
+            ..EncodedImage::new(data, img.width(), img.height())
+        })
     }
 
-    fn compress_to_webp(
-        file: &ImageFile,
-        output_path: &Path,
+    /// Quality range searched to meet a size limit, or `None` if the output
+    /// format has no quality to trade for size.
+    fn quality_range(settings: &CompressionSettings) -> Option<(u8, u8)> {
+        match settings.output_format {
+            OutputFormat::Jpeg | OutputFormat::Avif => Some((1, 100)),
+            OutputFormat::WebP if settings.webp.mode == WebPMode::Lossy => Some((0, 100)),
+            // Lossless-only candidates keep quality 100
+            OutputFormat::JpegXl if !settings.lossless_only => Some((0, 100)),
+            OutputFormat::JpegXl
+            | OutputFormat::Png
+            | OutputFormat::WebP
+            | OutputFormat::Gif
+            | OutputFormat::Auto => None,
+        }
+    }
+
+    /// Returns `settings` with the quality of the output format replaced by `quality`.
+    fn with_quality(settings: &CompressionSettings, quality: f32) -> CompressionSettings {
+        let mut settings = settings.clone();
+        match settings.output_format {
+            OutputFormat::Jpeg => settings.jpeg_quality = quality as u8,
+            OutputFormat::WebP => settings.webp_quality = quality,
+            OutputFormat::Avif => settings.avif_quality = quality,
+            OutputFormat::JpegXl => {
+                settings.jxl_quality = quality;
+                settings.jxl.lossless_jpeg = false;
+            }
+            OutputFormat::Png | OutputFormat::Gif | OutputFormat::Auto => {}
+        }
+        settings
+    }
+
+    /// Returns `true` if `file` is a JPEG that is losslessly repackaged as
+    /// JPEG XL, which is only possible while it keeps its size.
+    fn transcodes_jpeg(file: &ImageFile, settings: &CompressionSettings) -> bool {
+        settings.output_format == OutputFormat::JpegXl
+            && settings.jxl.lossless_jpeg
+            && file.format == ImageFormat::Jpeg
+            && !(settings.resize_images
+                && Self::upright_dimensions(&file.path).is_ok_and(|(width, height)| {
+                    resize::changes_size(&settings.resize, width, height)
+                }))
+    }
+
+    fn compress_to_png(
+        img: &DynamicImage,
         settings: &CompressionSettings,
-    ) -> Result<u64, String> {
-        let img = image::open(&file.path).map_err(|e| format!("Failed to load image: {}", e))?;
+        cancel_token: &CancellationToken,
+    ) -> Result<Vec<u8>, PixelGuardError> {
+        let mut png_data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png_data), image::ImageFormat::Png)
+            .map_err(|e| PixelGuardError::encode("PNG", e))?;
+
+        // Optimize with oxipng
+        Self::optimize_png(png_data, Self::png_options(settings), cancel_token)
+    }
 
+    fn png_options(settings: &CompressionSettings) -> oxipng::Options {
+        let png = &settings.png;
+        let mut options = oxipng::Options {
+            optimize_alpha: true,
+            strip: if settings.metadata.strip_all {
+                oxipng::StripChunks::Safe
+            } else {
+                oxipng::StripChunks::None
+            },
+            interlace: Some(if png.interlace {
+                oxipng::Interlacing::Adam7
+            } else {
+                oxipng::Interlacing::None
+            }),
+            bit_depth_reduction: png.bit_depth_reduction,
+            color_type_reduction: png.color_type_reduction,
+            palette_reduction: png.palette_reduction,
+            grayscale_reduction: png.grayscale_reduction,
+            ..oxipng::Options::from_preset(settings.png_level)
+        };
+
+        if !png.filters.is_empty() {
+            options.filter = png
+                .filters
+                .iter()
+                .map(|&filter| Self::row_filter(filter))
+                .collect();
+        }
+
+        if let PngDeflater::Zopfli { iterations } = png.deflater
+            && let Some(iterations) = NonZeroU8::new(iterations)
+        {
+            options.deflate = oxipng::Deflaters::Zopfli { iterations };
+        }
+
+        options
+    }
+
+    fn row_filter(filter: PngFilter) -> oxipng::RowFilter {
+        match filter {
+            PngFilter::None => oxipng::RowFilter::None,
+            PngFilter::Sub => oxipng::RowFilter::Sub,
+            PngFilter::Up => oxipng::RowFilter::Up,
+            PngFilter::Average => oxipng::RowFilter::Average,
+            PngFilter::Paeth => oxipng::RowFilter::Paeth,
+            PngFilter::MinSum => oxipng::RowFilter::MinSum,
+            PngFilter::Entropy => oxipng::RowFilter::Entropy,
+            PngFilter::Bigrams => oxipng::RowFilter::Bigrams,
+            PngFilter::BigEnt => oxipng::RowFilter::BigEnt,
+            PngFilter::Brute => oxipng::RowFilter::Brute,
+        }
+    }
+
+    /// Runs oxipng on a helper thread so cancellation does not have to wait for
+    /// a slow optimization. A cancelled run is left to finish in the background
+    /// and its output is discarded.
+    fn optimize_png(
+        png_data: Vec<u8>,
+        options: oxipng::Options,
+        cancel_token: &CancellationToken,
+    ) -> Result<Vec<u8>, PixelGuardError> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(oxipng::optimize_from_memory(&png_data, &options));
+        });
+
+        loop {
+            match receiver.recv_timeout(CANCEL_POLL_INTERVAL) {
+                Ok(result) => {
+                    return result.map_err(|e| PixelGuardError::encode("PNG", e));
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if cancel_token.is_cancelled() {
+                        return Err(PixelGuardError::Cancelled);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(PixelGuardError::encode_message(
+                        "PNG",
+                        "optimizer stopped unexpectedly",
+                    ));
+                }
+            }
+        }
+    }
+
+    fn compress_to_webp(
+        img: &DynamicImage,
+        settings: &CompressionSettings,
+    ) -> Result<Vec<u8>, PixelGuardError> {
         let rgba_img = img.to_rgba8();
         let (width, height) = rgba_img.dimensions();
 
         let encoder = webp::Encoder::from_rgba(&rgba_img, width, height);
-        let encoded = encoder.encode(settings.webp_quality);
+        let encoded = encoder
+            .encode_advanced(&Self::webp_config(settings)?)
+            .map_err(|e| PixelGuardError::encode_message("WebP", format!("{:?}", e)))?;
 
-        std::fs::write(output_path, &*encoded)
-            .map_err(|e| format!("Failed to write WebP: {}", e))?;
+        Ok(encoded.to_vec())
+    }
 
-        Ok(encoded.len() as u64)
+    fn webp_config(settings: &CompressionSettings) -> Result<webp::WebPConfig, PixelGuardError> {
+        let mut config = webp::WebPConfig::new()
+            .map_err(|_| PixelGuardError::encode_message("WebP", "failed to initialise encoder"))?;
+        config.method = settings.webp.method.into();
+        match settings.webp.mode {
+            WebPMode::Lossy => {
+                config.quality = settings.webp_quality;
+            }
+            WebPMode::Lossless => {
+                config.lossless = 1;
+                // Keep the color of fully transparent pixels so the output is pixel-exact
+                config.exact = 1;
+            }
+            WebPMode::NearLossless => {
+                config.lossless = 1;
+                config.near_lossless = settings.webp.near_lossless.into();
+            }
+        }
+        Ok(config)
     }
 
     fn compress_to_jpeg(
-        file: &ImageFile,
-        output_path: &Path,
+        img: &DynamicImage,
         settings: &CompressionSettings,
-    ) -> Result<u64, String> {
-        let img = image::open(&file.path).map_err(|e| format!("Failed to load image: {}", e))?;
-
+    ) -> Result<Vec<u8>, PixelGuardError> {
         let rgb_img = img.to_rgb8();
-        let mut output = std::fs::File::create(output_path)
-            .map_err(|e| format!("Failed to create output file: {}", e))?;
+        let mut output = Vec::new();
 
         let mut encoder =
             image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, settings.jpeg_quality);
@@ -153,35 +1168,473 @@ impl CompressionEngine {
 
         encoder
             .encode(&rgb_img, width, height, image::ExtendedColorType::Rgb8)
-            .map_err(|e| format!("JPEG encoding failed: {}", e))?;
+            .map_err(|e| PixelGuardError::encode("JPEG", e))?;
 
-        let metadata = std::fs::metadata(output_path)
-            .map_err(|e| format!("Failed to read output size: {}", e))?;
-        Ok(metadata.len())
+        Ok(output)
     }
 
-    fn generate_output_path(file: &ImageFile, settings: &CompressionSettings) -> PathBuf {
-        let extension = match settings.output_format {
-            OutputFormat::Png => "png",
-            OutputFormat::WebP => "webp",
-            OutputFormat::Jpeg => "jpg",
-        };
+    /// Writes `data` to a hidden file next to `output_path` and renames it into
+    /// place, so the output path never holds a partially written image.
+    ///
+    /// Returns the path that was written, which differs from `output_path` when
+    /// `policy` renames around an existing file.
+    pub(crate) fn write_output(
+        output_path: &Path,
+        data: &[u8],
+        policy: ConflictPolicy,
+    ) -> Result<PathBuf, PixelGuardError> {
+        let output_path = Self::claim_output(output_path, policy)?;
+        if let Err(error) = Self::write_claimed(&output_path, data) {
+            if policy != ConflictPolicy::Overwrite {
+                let _ = std::fs::remove_file(&output_path);
+            }
+            return Err(error);
+        }
+        Ok(output_path)
+    }
+
+    /// Creates the folders of `output_path` and, unless `policy` overwrites,
+    /// claims the name with an empty placeholder, so two workers producing
+    /// the same file name cannot both write it. Returns the claimed path.
+    fn claim_output(
+        output_path: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<PathBuf, PixelGuardError> {
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| PixelGuardError::write(parent, e))?;
+        }
+
+        match policy {
+            ConflictPolicy::Overwrite => Ok(output_path.to_path_buf()),
+            ConflictPolicy::Skip | ConflictPolicy::Fail => {
+                Self::reserve_output(output_path)?;
+                Ok(output_path.to_path_buf())
+            }
+            ConflictPolicy::Rename => Self::reserve_unique_output(output_path),
+        }
+    }
+
+    /// Writes `data` to a hidden file next to `output_path` and renames it
+    /// over the path.
+    fn write_claimed(output_path: &Path, data: &[u8]) -> Result<(), PixelGuardError> {
+        let file_name = output_path.file_name().unwrap_or_default();
+        let temp_path =
+            output_path.with_file_name(format!(".{}.partial", file_name.to_string_lossy()));
+
+        std::fs::write(&temp_path, data)
+            .and_then(|()| std::fs::rename(&temp_path, output_path))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&temp_path);
+                PixelGuardError::write(output_path, e)
+            })
+    }
+
+    /// Creates an empty placeholder at `path`, failing if it already exists.
+    fn reserve_output(path: &Path) -> Result<(), PixelGuardError> {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(PixelGuardError::OutputExists {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) => Err(PixelGuardError::write(path, e)),
+        }
+    }
 
-        let stem = file.path.file_stem().unwrap_or_default();
-        let filename = format!("{}_compressed.{}", stem.to_string_lossy(), extension);
+    /// Reserves `path`, or the first free `name-1.ext`, `name-2.ext`, ... next to it.
+    fn reserve_unique_output(path: &Path) -> Result<PathBuf, PixelGuardError> {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
 
-        Path::new(&settings.output_directory).join(filename)
+        for suffix in 0..MAX_RENAME_ATTEMPTS {
+            let candidate = if suffix == 0 {
+                path.to_path_buf()
+            } else {
+                path.with_file_name(format!("{}-{}{}", stem, suffix, extension))
+            };
+            match Self::reserve_output(&candidate) {
+                Err(PixelGuardError::OutputExists { .. }) => continue,
+                result => return result.map(|()| candidate),
+            }
+        }
+        Err(PixelGuardError::OutputExists {
+            path: path.to_path_buf(),
+        })
     }
+
+    fn generate_output_path(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        encoded: &EncodedImage,
+    ) -> Result<PathBuf, PixelGuardError> {
+        let digest = Sha256::digest(&encoded.data);
+        let hash8: String = digest[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        // Tokens describe the output that was actually encoded
+        let mut settings = Cow::Borrowed(settings);
+        if let Some(format) = &encoded.format {
+            settings = Cow::Owned(Self::with_format(&settings, format.clone()));
+        }
+        if let Some(quality) = encoded.quality {
+            settings = Cow::Owned(Self::with_quality(&settings, quality));
+        }
+        Self::output_path(file, &settings, (encoded.width, encoded.height), &hash8)
+    }
+
+    /// Shows where `file` would be written with `settings`, without compressing it.
+    ///
+    /// `dimensions` stand in for the output size, usually the input's. The
+    /// output hash is not known yet, so `{hash8}` is shown as `xxxxxxxx`.
+    pub fn preview_output_path(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        dimensions: (u32, u32),
+    ) -> Result<PathBuf, PixelGuardError> {
+        Self::output_path(file, settings, dimensions, "xxxxxxxx")
+    }
+
+    fn output_path(
+        file: &ImageFile,
+        settings: &CompressionSettings,
+        (width, height): (u32, u32),
+        hash8: &str,
+    ) -> Result<PathBuf, PixelGuardError> {
+        let format = &settings.output_format;
+        if settings.in_place {
+            return Ok(Self::in_place_path(&file.path, format));
+        }
+
+        let stem = file.path.file_stem().unwrap_or_default().to_string_lossy();
+        let filename = template::render(&settings.filename_template, |token| match token {
+            "stem" => stem.to_string(),
+            "ext" => format.extension().to_string(),
+            "format" => format.name().to_string(),
+            "width" => width.to_string(),
+            "height" => height.to_string(),
+            "quality" => Self::quality_label(file, settings),
+            "hash8" => hash8.to_string(),
+            "date" => template::current_date(),
+            _ => String::new(),
+        })?;
+
+        let mut directory = PathBuf::from(&settings.output_directory);
+        if settings.mirror_structure
+            && let Some(relative) = Self::relative_directory(&file.path, &settings.input_root)
+        {
+            directory.push(relative);
+        }
+        Ok(directory.join(filename))
+    }
+
+    /// Returns `path` if it already has an extension of `format`, otherwise
+    /// `path` with the extension of `format`.
+    fn in_place_path(path: &Path, format: &OutputFormat) -> PathBuf {
+        let same_format = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                extension.eq_ignore_ascii_case(format.extension())
+                    || extension.eq_ignore_ascii_case(format.name())
+            });
+        if same_format {
+            path.to_path_buf()
+        } else {
+            path.with_extension(format.extension())
+        }
+    }
+
+    /// The quality setting used for `file`, or `lossless` if nothing is lost.
+    fn quality_label(file: &ImageFile, settings: &CompressionSettings) -> String {
+        let lossless = "lossless".to_string();
+        match settings.output_format {
+            OutputFormat::Png => lossless,
+            OutputFormat::WebP => match settings.webp.mode {
+                WebPMode::Lossy => settings.webp_quality.to_string(),
+                WebPMode::NearLossless => settings.webp.near_lossless.to_string(),
+                WebPMode::Lossless => lossless,
+            },
+            OutputFormat::Jpeg => settings.jpeg_quality.to_string(),
+            OutputFormat::Avif => settings.avif_quality.to_string(),
+            OutputFormat::JpegXl if Self::transcodes_jpeg(file, settings) => lossless,
+            OutputFormat::JpegXl if settings.jxl_quality >= 100.0 => lossless,
+            OutputFormat::JpegXl => settings.jxl_quality.to_string(),
+            OutputFormat::Gif => "palette".to_string(),
+            OutputFormat::Auto => "auto".to_string(),
+        }
+    }
+
+    /// Returns the folder of `path` relative to `root`, or `None` if `path` is not inside `root`.
+    fn relative_directory(path: &Path, root: &str) -> Option<PathBuf> {
+        let path = std::path::absolute(path).ok()?;
+        let root = std::path::absolute(root).ok()?;
+        let relative = path.parent()?.strip_prefix(root).ok()?;
+        // `..` would let the output escape the output directory
+        relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            .then(|| relative.to_path_buf())
+    }
+}
+
+/// Text of a panic payload, which is a `&str` or `String` for `panic!` messages.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
 }
 
+/// Event sent by [`CompressionEngine::compress_files`] while a batch runs.
 #[derive(Clone, Debug)]
 pub enum CompressionProgress {
+    /// A file is about to be compressed. Files run concurrently, so several
+    /// may be in progress at once.
     Processing {
+        /// One-based position of the file in the batch.
         current: usize,
+        /// Number of files in the batch.
         total: usize,
+        /// Name of the file.
         filename: String,
     },
+    /// Fraction of the batch that has finished, from 0.0 to 1.0.
     Progress(f32),
+    /// The batch has finished.
     Complete,
-    Error(String),
+    /// The batch could not be started.
+    Error(PixelGuardError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionStatus;
+
+    /// Writes a 64x64 noise JPEG at low quality, which is far smaller than
+    /// any lossless encoding of its pixels.
+    fn write_noise_jpeg(path: &Path) {
+        let mut state = 1u32;
+        let image = image::RgbImage::from_fn(64, 64, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let [r, g, b, _] = state.to_be_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut data = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, 20)
+            .encode_image(&image)
+            .unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    fn compress(path: &Path, settings: &CompressionSettings) -> CompressionResult {
+        let file = FileManager::analyze_file(path.to_path_buf()).unwrap();
+        CompressionEngine::compress_single_file(&file, settings, &CancellationToken::new())
+    }
+
+    #[test]
+    fn a_larger_output_keeps_the_original() {
+        let directory = tempfile::tempdir().unwrap();
+        let input = directory.path().join("noise.jpg");
+        write_noise_jpeg(&input);
+        let output_directory = directory.path().join("out");
+        let settings = CompressionSettings {
+            output_format: OutputFormat::Png,
+            require_savings: true,
+            output_directory: output_directory.display().to_string(),
+            ..CompressionSettings::default()
+        };
+
+        let result = compress(&input, &settings);
+        assert!(
+            matches!(&result.status, CompressionStatus::Skipped(reason) if reason.contains("larger")),
+            "{:?}",
+            result.status
+        );
+        assert!(!output_directory.exists() || output_directory.read_dir().unwrap().count() == 0);
+
+        let settings = CompressionSettings {
+            copy_original_on_skip: true,
+            ..settings
+        };
+        let result = compress(&input, &settings);
+        assert!(
+            matches!(&result.status, CompressionStatus::Skipped(reason) if reason.contains("copied the original")),
+            "{:?}",
+            result.status
+        );
+        assert_eq!(result.output_path.extension().unwrap(), "jpg");
+        assert_eq!(
+            std::fs::read(&result.output_path).unwrap(),
+            std::fs::read(&input).unwrap()
+        );
+        assert_eq!(result.compressed_size, result.original_size);
+    }
+
+    #[test]
+    fn auto_keeps_the_smallest_candidate_under_its_own_extension() {
+        let directory = tempfile::tempdir().unwrap();
+        let input = directory.path().join("gradient.png");
+        image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 128]))
+            .save(&input)
+            .unwrap();
+        let settings = |format, folder: &str| CompressionSettings {
+            output_format: format,
+            output_directory: directory.path().join(folder).display().to_string(),
+            ..CompressionSettings::default()
+        };
+
+        let result = compress(&input, &settings(OutputFormat::Auto, "auto"));
+        assert!(matches!(result.status, CompressionStatus::Success));
+        let format = result.output_format.clone().unwrap();
+        assert_eq!(result.output_path.extension().unwrap(), format.extension());
+
+        // Candidates that cannot be encoded here, such as JPEG XL without cjxl, drop out
+        let auto = settings(OutputFormat::Auto, "auto");
+        let smallest = CompressionEngine::auto_candidates(&auto, false, false)
+            .into_iter()
+            .map(|candidate| compress(&input, &settings(candidate.clone(), candidate.name())))
+            .filter(|result| matches!(result.status, CompressionStatus::Success))
+            .min_by_key(|result| result.compressed_size)
+            .unwrap();
+        assert_eq!(result.compressed_size, smallest.compressed_size);
+        assert_eq!(result.output_format, smallest.output_format);
+    }
+
+    #[test]
+    fn picked_qualities_record_their_search() {
+        let directory = tempfile::tempdir().unwrap();
+        let input = directory.path().join("noise.jpg");
+        write_noise_jpeg(&input);
+        let settings = CompressionSettings {
+            output_format: OutputFormat::Jpeg,
+            output_directory: directory.path().join("out").display().to_string(),
+            conflict_policy: ConflictPolicy::Overwrite,
+            ..CompressionSettings::default()
+        };
+
+        let result = compress(&input, &settings);
+        assert_eq!((result.quality, result.quality_search), (None, None));
+
+        let result = compress(
+            &input,
+            &CompressionSettings {
+                limit_file_size: true,
+                max_file_size: 1_000_000,
+                ..settings.clone()
+            },
+        );
+        assert!(result.quality.is_some());
+        assert_eq!(result.quality_search, Some(QualitySearch::SizeLimit));
+
+        let result = compress(
+            &input,
+            &CompressionSettings {
+                match_visual_quality: true,
+                ..settings
+            },
+        );
+        assert!(result.quality.is_some());
+        assert_eq!(result.quality_search, Some(QualitySearch::VisualTarget));
+    }
+
+    #[test]
+    fn panic_messages_are_kept() {
+        let payload = panic::catch_unwind(|| panic!("bad image")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "bad image");
+        let payload = panic::catch_unwind(|| panic!("{} bytes", 3)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "3 bytes");
+    }
+
+    #[test]
+    fn overwrite_replaces_an_existing_output() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.png");
+        std::fs::write(&path, b"old").unwrap();
+
+        let written =
+            CompressionEngine::write_output(&path, b"new", ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(written, path);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn skip_and_fail_leave_an_existing_output_alone() {
+        for policy in [ConflictPolicy::Skip, ConflictPolicy::Fail] {
+            let directory = tempfile::tempdir().unwrap();
+            let path = directory.path().join("photo.png");
+            std::fs::write(&path, b"old").unwrap();
+
+            let written = CompressionEngine::write_output(&path, b"new", policy);
+            assert!(matches!(written, Err(PixelGuardError::OutputExists { .. })));
+            assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        }
+    }
+
+    #[test]
+    fn every_policy_writes_a_new_output_and_its_folders() {
+        for policy in [
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::Skip,
+            ConflictPolicy::Rename,
+            ConflictPolicy::Fail,
+        ] {
+            let directory = tempfile::tempdir().unwrap();
+            let path = directory.path().join("nested/photo.png");
+
+            let written = CompressionEngine::write_output(&path, b"new", policy).unwrap();
+            assert_eq!(written, path);
+            assert_eq!(std::fs::read(&path).unwrap(), b"new");
+            // No temporary file is left behind
+            assert_eq!(
+                std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn rename_picks_the_first_free_numbered_name() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.png");
+        std::fs::write(&path, b"first").unwrap();
+        std::fs::write(directory.path().join("photo-1.png"), b"second").unwrap();
+
+        let written =
+            CompressionEngine::write_output(&path, b"third", ConflictPolicy::Rename).unwrap();
+        assert_eq!(written, directory.path().join("photo-2.png"));
+        assert_eq!(std::fs::read(&written).unwrap(), b"third");
+
+        let written =
+            CompressionEngine::write_output(&path, b"fourth", ConflictPolicy::Rename).unwrap();
+        assert_eq!(written, directory.path().join("photo-3.png"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+    }
+
+    #[test]
+    fn a_claimed_name_cannot_be_claimed_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.png");
+
+        let claimed = CompressionEngine::claim_output(&path, ConflictPolicy::Fail).unwrap();
+        assert_eq!(claimed, path);
+        assert!(matches!(
+            CompressionEngine::claim_output(&path, ConflictPolicy::Skip),
+            Err(PixelGuardError::OutputExists { .. })
+        ));
+        assert_eq!(
+            CompressionEngine::claim_output(&path, ConflictPolicy::Rename).unwrap(),
+            directory.path().join("photo-1.png")
+        );
+    }
 }
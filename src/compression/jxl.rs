@@ -0,0 +1,136 @@
+use crate::compression::cancel::CANCEL_POLL_INTERVAL;
+use crate::compression::metadata::Metadata;
+use crate::compression::{CancellationToken, JpegXlSettings};
+use crate::error::PixelGuardError;
+use image::DynamicImage;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// Environment variable overriding the `cjxl` executable that is used.
+const CJXL_ENV: &str = "PIXELGUARD_CJXL";
+
+/// Losslessly repackages `jpeg` as JPEG XL with libjxl's `cjxl` tool.
+/// `djxl` turns the result back into `jpeg` byte for byte.
+///
+/// No Rust JPEG XL encoder supports JPEG reconstruction, so the reference
+/// encoder is run as a subprocess.
+pub(crate) fn transcode_jpeg(
+    jpeg: &[u8],
+    settings: &JpegXlSettings,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<u8>, PixelGuardError> {
+    let work_dir = work_dir()?;
+    let input_path = work_dir.path().join("input.jpg");
+    let output_path = work_dir.path().join("output.jxl");
+    std::fs::write(&input_path, jpeg).map_err(|e| PixelGuardError::write(&input_path, e))?;
+    let effort = format!("--effort={}", settings.effort);
+
+    run_cjxl(
+        &[
+            input_path.as_os_str(),
+            output_path.as_os_str(),
+            "--lossless_jpeg=1".as_ref(),
+            effort.as_ref(),
+        ],
+        cancel_token,
+    )?;
+
+    std::fs::read(&output_path).map_err(|e| PixelGuardError::read(&output_path, e))
+}
+
+/// Encodes `img` as JPEG XL with `cjxl`, handing it over as a PNG file.
+/// `metadata` is put into that PNG, from which `cjxl` copies it.
+pub(crate) fn encode(
+    img: &DynamicImage,
+    quality: f32,
+    settings: &JpegXlSettings,
+    metadata: &Metadata,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<u8>, PixelGuardError> {
+    let work_dir = work_dir()?;
+    let input_path = work_dir.path().join("input.png");
+    let output_path = work_dir.path().join("output.jxl");
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(|e| PixelGuardError::encode("JPEG XL", e))?;
+    std::fs::write(&input_path, metadata.embed_png(png)?)
+        .map_err(|e| PixelGuardError::write(&input_path, e))?;
+
+    let quality = format!("--quality={}", quality);
+    let effort = format!("--effort={}", settings.effort);
+    run_cjxl(
+        &[
+            input_path.as_os_str(),
+            output_path.as_os_str(),
+            "--lossless_jpeg=0".as_ref(),
+            quality.as_ref(),
+            effort.as_ref(),
+        ],
+        cancel_token,
+    )?;
+
+    std::fs::read(&output_path).map_err(|e| PixelGuardError::read(&output_path, e))
+}
+
+fn work_dir() -> Result<tempfile::TempDir, PixelGuardError> {
+    tempfile::tempdir().map_err(|e| PixelGuardError::write(&std::env::temp_dir(), e))
+}
+
+fn run_cjxl(
+    args: &[&std::ffi::OsStr],
+    cancel_token: &CancellationToken,
+) -> Result<(), PixelGuardError> {
+    let program = std::env::var_os(CJXL_ENV).unwrap_or_else(|| "cjxl".into());
+
+    let mut child = Command::new(&program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => PixelGuardError::MissingTool {
+                tool: Path::new(&program).display().to_string(),
+                source: Arc::new(e),
+            },
+            _ => PixelGuardError::encode("JPEG XL", e),
+        })?;
+
+    // Read while cjxl runs, since it blocks once the pipe buffer is full
+    let stderr = child.stderr.take().map(|mut pipe| {
+        std::thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = pipe.read_to_string(&mut stderr);
+            stderr
+        })
+    });
+
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .map_err(|e| PixelGuardError::encode("JPEG XL", e))?
+        {
+            if status.success() {
+                return Ok(());
+            }
+
+            let stderr = stderr
+                .and_then(|reader| reader.join().ok())
+                .unwrap_or_default();
+            let reason = stderr.lines().last().unwrap_or("no output");
+            return Err(PixelGuardError::encode_message(
+                "JPEG XL",
+                format!("cjxl failed ({}): {}", status, reason),
+            ));
+        }
+
+        if cancel_token.is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(PixelGuardError::Cancelled);
+        }
+
+        std::thread::sleep(CANCEL_POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,1025 @@
+use crate::compression::{MetadataKind, MetadataPolicy};
+use crate::error::PixelGuardError;
+use crate::file::{ImageFile, ImageFormat};
+use exif::experimental::Writer as ExifWriter;
+use exif::{Context, Exif, Field, In, Reader as ExifReader, Tag};
+use image::{ImageDecoder, ImageReader};
+use img_parts::jpeg::{Jpeg, JpegSegment, markers};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{
+    CHUNK_ALPH, CHUNK_ANIM, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP, WebP,
+};
+use img_parts::{Bytes, ImageEXIF, ImageICC};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader as XmlReader, Writer as XmlWriter};
+use std::io::{self, Cursor};
+
+/// Prefix of EXIF data in JPEG APP1 segments, and sometimes in WebP.
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// Prefix of XMP packets in JPEG APP1 segments.
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Prefix of ICC profile chunks in JPEG APP2 segments.
+const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+
+/// Keyword of the PNG `iTXt` chunk holding an XMP packet.
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Largest payload of a JPEG marker segment.
+const MAX_JPEG_SEGMENT: usize = 65533;
+
+/// ICC profile bytes per JPEG APP2 segment, after the 14 byte header.
+const ICC_JPEG_CHUNK: usize = MAX_JPEG_SEGMENT - 14;
+
+/// EXIF tag holding the orientation.
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// PNG chunks holding text, in which XMP is also stored.
+const PNG_TEXT_CHUNKS: [[u8; 4]; 3] = [*b"tEXt", *b"zTXt", *b"iTXt"];
+
+/// EXIF fields describing the layout of the block rather than the image,
+/// which the writer regenerates.
+const LAYOUT_TAGS: [Tag; 7] = [
+    Tag::ExifIFDPointer,
+    Tag::GPSInfoIFDPointer,
+    Tag::InteropIFDPointer,
+    Tag::JPEGInterchangeFormat,
+    Tag::JPEGInterchangeFormatLength,
+    Tag::StripOffsets,
+    Tag::StripByteCounts,
+];
+
+/// Kinds a block that cannot be parsed may hold, so it is only kept if the
+/// policy keeps all of them.
+const OPAQUE_KINDS: [MetadataKind; 5] = [
+    MetadataKind::Location,
+    MetadataKind::SerialNumbers,
+    MetadataKind::Thumbnail,
+    MetadataKind::Copyright,
+    MetadataKind::Other,
+];
+
+/// EXIF, XMP, ICC, PNG text and other JPEG metadata carried from an input to its output.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metadata {
+    /// TIFF structured EXIF data, without the `Exif\0\0` prefix.
+    pub(crate) exif: Option<Vec<u8>>,
+    /// XMP packet.
+    pub(crate) xmp: Option<Vec<u8>>,
+    /// ICC color profile.
+    pub(crate) icc: Option<Vec<u8>>,
+    /// `tEXt`, `zTXt` and `iTXt` chunks of PNG inputs, apart from XMP.
+    pub(crate) png_text: Vec<PngChunk>,
+    /// Metadata segments of JPEG inputs apart from EXIF, XMP and ICC, such
+    /// as IPTC, comments and extended XMP. Only JPEG outputs can hold them.
+    pub(crate) jpeg_segments: Vec<JpegSegment>,
+}
+
+impl Metadata {
+    /// Reads the metadata of `file`. Blocks that cannot be parsed are left
+    /// out rather than failing the file.
+    pub(crate) fn read(file: &ImageFile) -> Result<Self, PixelGuardError> {
+        let data = Bytes::from(
+            std::fs::read(&file.path).map_err(|e| PixelGuardError::read(&file.path, e))?,
+        );
+        Ok(match file.format {
+            ImageFormat::Jpeg => Jpeg::from_bytes(data).map_or_else(
+                |_| Self::default(),
+                |jpeg| Self {
+                    exif: jpeg.exif().map(Vec::from),
+                    xmp: jpeg
+                        .segments_by_marker(markers::APP1)
+                        .find_map(|segment| segment.contents().strip_prefix(XMP_PREFIX))
+                        .map(Vec::from),
+                    icc: jpeg.icc_profile().map(Vec::from),
+                    jpeg_segments: jpeg
+                        .segments()
+                        .iter()
+                        .filter(|segment| {
+                            is_jpeg_metadata(segment) && !is_read_jpeg_segment(segment)
+                        })
+                        .cloned()
+                        .collect(),
+                    ..Self::default()
+                },
+            ),
+            ImageFormat::Png => Png::from_bytes(data).map_or_else(
+                |_| Self::default(),
+                |png| Self {
+                    exif: png.exif().map(Vec::from),
+                    xmp: png
+                        .chunks_by_type(*b"iTXt")
+                        .find_map(|chunk| itxt_xmp(chunk.contents())),
+                    icc: png.icc_profile().map(Vec::from),
+                    png_text: png
+                        .chunks()
+                        .iter()
+                        .filter(|chunk| {
+                            PNG_TEXT_CHUNKS.contains(&chunk.kind())
+                                && itxt_xmp(chunk.contents()).is_none()
+                        })
+                        .cloned()
+                        .collect(),
+                    ..Self::default()
+                },
+            ),
+            ImageFormat::WebP => WebP::from_bytes(data).map_or_else(
+                |_| Self::default(),
+                |webp| {
+                    let chunk = |id| {
+                        webp.chunk_by_id(id)
+                            .and_then(|chunk| chunk.content().data())
+                    };
+                    Self {
+                        exif: chunk(CHUNK_EXIF)
+                            .map(|exif| exif.strip_prefix(EXIF_PREFIX).unwrap_or(exif).to_vec()),
+                        xmp: chunk(CHUNK_XMP).map(|xmp| xmp.to_vec()),
+                        icc: chunk(CHUNK_ICCP).map(|icc| icc.to_vec()),
+                        ..Self::default()
+                    }
+                },
+            ),
+            _ => {
+                // Other formats have no XMP support in the decoders
+                let Ok(mut decoder) = ImageReader::open(&file.path)
+                    .map_err(io::Error::other)
+                    .and_then(|reader| reader.into_decoder().map_err(io::Error::other))
+                else {
+                    return Ok(Self::default());
+                };
+                Self {
+                    exif: decoder.exif_metadata().ok().flatten(),
+                    xmp: None,
+                    icc: decoder.icc_profile().ok().flatten(),
+                    ..Self::default()
+                }
+            }
+        })
+    }
+
+    /// Returns `true` if there is nothing to carry over.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exif.is_none()
+            && self.xmp.is_none()
+            && self.icc.is_none()
+            && self.png_text.is_empty()
+            && self.jpeg_segments.is_empty()
+    }
+
+    /// Removes what `policy` does not keep, returning the rest and the kinds
+    /// that were removed, in order and without repeats. The EXIF orientation
+    /// is kept regardless, since it describes how to show the pixels.
+    pub(crate) fn filter(self, policy: &MetadataPolicy) -> (Self, Vec<MetadataKind>) {
+        let mut removed = Vec::new();
+        let icc = self
+            .icc
+            .filter(|_| keep(MetadataKind::ColorProfile, policy, &mut removed));
+        let exif = self
+            .exif
+            .and_then(|exif| filter_exif(exif, policy, &mut removed));
+        let xmp = self
+            .xmp
+            .and_then(|xmp| filter_xmp(xmp, policy, &mut removed));
+        let png_text = self
+            .png_text
+            .into_iter()
+            .filter(|chunk| {
+                let kind = match text_keyword(chunk.contents()) {
+                    b"Author" | b"Copyright" => MetadataKind::Copyright,
+                    // XMP that could not be read, so it may hold anything
+                    XMP_KEYWORD => return keep_opaque(policy, &mut removed),
+                    // Profiles stored as hex text by ImageMagick
+                    keyword if keyword.starts_with(b"Raw profile type") => {
+                        return keep_opaque(policy, &mut removed);
+                    }
+                    _ => MetadataKind::Other,
+                };
+                keep(kind, policy, &mut removed)
+            })
+            .collect();
+        let jpeg_segments = self
+            .jpeg_segments
+            .into_iter()
+            .filter(|segment| {
+                if segment.marker() == markers::COM {
+                    return keep(MetadataKind::Other, policy, &mut removed);
+                }
+                // IPTC, extended XMP and blocks of other programs may hold anything
+                keep_opaque(policy, &mut removed)
+            })
+            .collect();
+
+        removed.sort();
+        removed.dedup();
+        let metadata = Self {
+            exif,
+            xmp,
+            icc,
+            png_text,
+            jpeg_segments,
+        };
+        (metadata, removed)
+    }
+
+    /// Returns the metadata for pixels that were already turned upright,
+    /// with the EXIF orientation reset and the XMP one removed so viewers do
+    /// not turn them again. EXIF data or XMP holding nothing but the
+    /// orientation is dropped.
+    pub(crate) fn upright(mut self) -> Self {
+        if let Some(xmp) = &self.xmp {
+            // XMP that cannot be parsed is kept as it is
+            if let Ok(rewritten) = rewrite_xmp(xmp, |name| name != b"Orientation") {
+                self.xmp = rewritten;
+            }
+        }
+        if let Some(exif) = &mut self.exif {
+            let only_orientation = ExifReader::new()
+                .read_raw(exif.clone())
+                .is_ok_and(|parsed| {
+                    parsed.fields().all(|field| {
+                        field.tag == Tag::Orientation || LAYOUT_TAGS.contains(&field.tag)
+                    })
+                });
+            if only_orientation {
+                self.exif = None;
+            } else {
+                reset_orientation(exif);
+            }
+        }
+        self
+    }
+
+    /// Replaces the metadata of an existing JPEG with this metadata, for
+    /// JPEGs that are repackaged rather than re-encoded. `removed` holds what
+    /// [`filter`](Self::filter) removed; the JPEG is returned untouched if
+    /// it is empty.
+    pub(crate) fn replace_in_jpeg(
+        &self,
+        data: Vec<u8>,
+        removed: &[MetadataKind],
+    ) -> Result<Vec<u8>, PixelGuardError> {
+        if removed.is_empty() {
+            return Ok(data);
+        }
+        let mut jpeg = Jpeg::from_bytes(data.into()).map_err(container_error("JPEG"))?;
+        jpeg.segments_mut()
+            .retain(|segment| !is_jpeg_metadata(segment));
+        self.insert_jpeg_segments(&mut jpeg);
+        Ok(jpeg.encoder().bytes().to_vec())
+    }
+
+    /// Adds the metadata to an encoded JPEG, after its JFIF header.
+    /// Blocks too large for a JPEG segment are left out.
+    pub(crate) fn embed_jpeg(&self, data: Vec<u8>) -> Result<Vec<u8>, PixelGuardError> {
+        if self.is_empty() {
+            return Ok(data);
+        }
+        let mut jpeg = Jpeg::from_bytes(data.into()).map_err(container_error("JPEG"))?;
+        self.insert_jpeg_segments(&mut jpeg);
+        Ok(jpeg.encoder().bytes().to_vec())
+    }
+
+    /// Inserts the metadata segments after the JFIF header of `jpeg`.
+    fn insert_jpeg_segments(&self, jpeg: &mut Jpeg) {
+        let mut segments = Vec::new();
+        let mut app_segment = |prefix: &[u8], payload: &[u8], marker| {
+            if prefix.len() + payload.len() <= MAX_JPEG_SEGMENT {
+                segments.push(JpegSegment::new_with_contents(
+                    marker,
+                    Bytes::from([prefix, payload].concat()),
+                ));
+            }
+        };
+        if let Some(exif) = &self.exif {
+            app_segment(EXIF_PREFIX, exif, markers::APP1);
+        }
+        if let Some(xmp) = &self.xmp {
+            app_segment(XMP_PREFIX, xmp, markers::APP1);
+        }
+        if let Some(icc) = &self.icc {
+            // Profiles are split over numbered APP2 segments
+            let chunks: Vec<_> = icc.chunks(ICC_JPEG_CHUNK).collect();
+            if let Ok(count) = u8::try_from(chunks.len()) {
+                for (index, chunk) in chunks.into_iter().enumerate() {
+                    let header = [ICC_PREFIX, &[index as u8 + 1, count]].concat();
+                    app_segment(&header, chunk, markers::APP2);
+                }
+            }
+        }
+        segments.extend(self.jpeg_segments.iter().cloned());
+
+        let position = jpeg
+            .segments()
+            .iter()
+            .position(|segment| segment.marker() != markers::APP0)
+            .unwrap_or(0);
+        jpeg.segments_mut().splice(position..position, segments);
+    }
+
+    /// Adds the metadata to an encoded PNG or APNG.
+    pub(crate) fn embed_png(&self, data: Vec<u8>) -> Result<Vec<u8>, PixelGuardError> {
+        if self.is_empty() {
+            return Ok(data);
+        }
+        let mut png = Png::from_bytes(data.into()).map_err(container_error("PNG"))?;
+
+        if let Some(icc) = &self.icc {
+            // A profile replaces the sRGB and gamma defaults
+            png.remove_chunks_by_type(*b"sRGB");
+            png.set_icc_profile(Some(Bytes::from(icc.clone())));
+        }
+        if let Some(exif) = &self.exif {
+            png.set_exif(Some(Bytes::from(exif.clone())));
+        }
+        if let Some(xmp) = &self.xmp {
+            // Uncompressed iTXt with empty language and translated keyword
+            let contents = [XMP_KEYWORD, b"\0\0\0\0\0", xmp].concat();
+            let end = png.chunks().len() - 1;
+            png.chunks_mut()
+                .insert(end, PngChunk::new(*b"iTXt", Bytes::from(contents)));
+        }
+        let end = png.chunks().len() - 1;
+        png.chunks_mut()
+            .splice(end..end, self.png_text.iter().cloned());
+        Ok(png.encoder().bytes().to_vec())
+    }
+
+    /// Adds the metadata to an encoded WebP, rewriting its `VP8X` header so
+    /// the feature flags match the chunks.
+    pub(crate) fn embed_webp(&self, data: Vec<u8>) -> Result<Vec<u8>, PixelGuardError> {
+        if self.is_empty() {
+            return Ok(data);
+        }
+        let webp = WebP::from_bytes(data.into()).map_err(container_error("WebP"))?;
+        let (width, height) = webp
+            .dimensions()
+            .ok_or_else(|| container_error("WebP")(img_parts::Error::WrongSignature))?;
+
+        let old_flags = webp
+            .chunk_by_id(CHUNK_VP8X)
+            .and_then(|chunk| chunk.content().data())
+            .and_then(|data| data.first().copied())
+            .unwrap_or(0);
+        let lossless_alpha = webp
+            .chunk_by_id(CHUNK_VP8L)
+            .and_then(|chunk| chunk.content().data())
+            .and_then(|data| data.get(4))
+            .is_some_and(|byte| byte & 0x10 != 0);
+        let has_alpha = old_flags & 0x10 != 0 || webp.has_chunk(CHUNK_ALPH) || lossless_alpha;
+
+        let mut flags = 0u8;
+        if self.icc.is_some() {
+            flags |= 0x20;
+        }
+        if has_alpha {
+            flags |= 0x10;
+        }
+        if self.exif.is_some() {
+            flags |= 0x08;
+        }
+        if self.xmp.is_some() {
+            flags |= 0x04;
+        }
+        if webp.has_chunk(CHUNK_ANIM) {
+            flags |= 0x02;
+        }
+
+        let mut header = vec![flags, 0, 0, 0];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+        let data_chunk =
+            |id, data: &[u8]| RiffChunk::new(id, RiffContent::Data(Bytes::from(data.to_vec())));
+        let mut chunks = vec![data_chunk(CHUNK_VP8X, &header)];
+        if let Some(icc) = &self.icc {
+            chunks.push(data_chunk(CHUNK_ICCP, icc));
+        }
+        chunks.extend(
+            webp.chunks()
+                .iter()
+                .filter(|chunk| {
+                    ![CHUNK_VP8X, CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP].contains(&chunk.id())
+                })
+                .cloned(),
+        );
+        if let Some(exif) = &self.exif {
+            chunks.push(data_chunk(CHUNK_EXIF, exif));
+        }
+        if let Some(xmp) = &self.xmp {
+            chunks.push(data_chunk(CHUNK_XMP, xmp));
+        }
+
+        let riff = RiffChunk::new(
+            *b"RIFF",
+            RiffContent::List {
+                kind: Some(*b"WEBP"),
+                subchunks: chunks,
+            },
+        );
+        Ok(riff.encoder().bytes().to_vec())
+    }
+}
+
+/// Returns `true` if `segment` holds metadata rather than image data. APP0
+/// holds the JFIF header and APP14 the Adobe color transform, which both
+/// describe the pixels.
+fn is_jpeg_metadata(segment: &JpegSegment) -> bool {
+    let marker = segment.marker();
+    marker == markers::COM
+        || ((markers::APP1..=markers::APP15).contains(&marker) && marker != markers::APP14)
+}
+
+/// Returns `true` if `segment` holds EXIF, XMP or ICC data, which
+/// [`Metadata::read`] parses rather than carrying over as it is.
+fn is_read_jpeg_segment(segment: &JpegSegment) -> bool {
+    let contents = segment.contents();
+    match segment.marker() {
+        markers::APP1 => contents.starts_with(EXIF_PREFIX) || contents.starts_with(XMP_PREFIX),
+        markers::APP2 => contents.starts_with(ICC_PREFIX),
+        _ => false,
+    }
+}
+
+/// Returns `true` if `policy` keeps metadata of `kind`, and records it in
+/// `removed` otherwise.
+fn keep(kind: MetadataKind, policy: &MetadataPolicy, removed: &mut Vec<MetadataKind>) -> bool {
+    let remove = policy.removes(kind);
+    if remove {
+        removed.push(kind);
+    }
+    !remove
+}
+
+/// Returns `true` if `policy` keeps a block that cannot be looked into,
+/// which is only the case if it keeps everything such a block may hold.
+fn keep_opaque(policy: &MetadataPolicy, removed: &mut Vec<MetadataKind>) -> bool {
+    let keep = keeps_opaque(policy);
+    if !keep {
+        removed.push(MetadataKind::Other);
+    }
+    keep
+}
+
+/// Returns `true` if `policy` keeps everything a block that cannot be
+/// looked into may hold.
+fn keeps_opaque(policy: &MetadataPolicy) -> bool {
+    !OPAQUE_KINDS.iter().any(|&kind| policy.removes(kind))
+}
+
+/// Rewrites `exif` without the fields `policy` removes, or returns `None` if
+/// no field is left.
+fn filter_exif(
+    exif: Vec<u8>,
+    policy: &MetadataPolicy,
+    removed: &mut Vec<MetadataKind>,
+) -> Option<Vec<u8>> {
+    let Ok(parsed) = ExifReader::new().read_raw(exif.clone()) else {
+        return keep_opaque(policy, removed).then_some(exif);
+    };
+
+    let removed_before = removed.len();
+    let mut writer = ExifWriter::new();
+    let mut kept_any = false;
+    for field in parsed.fields() {
+        if LAYOUT_TAGS.contains(&field.tag) {
+            continue;
+        }
+        if field.tag == Tag::Orientation || keep(exif_kind(field), policy, removed) {
+            writer.push_field(field);
+            kept_any = true;
+        }
+    }
+    if let Some(thumbnail) = exif_thumbnail(&parsed)
+        && keep(MetadataKind::Thumbnail, policy, removed)
+    {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    if !kept_any {
+        return None;
+    }
+
+    let mut filtered = Cursor::new(Vec::new());
+    match writer.write(&mut filtered, parsed.little_endian()) {
+        Ok(()) => Some(filtered.into_inner()),
+        Err(_) if removed.len() == removed_before => Some(exif),
+        Err(_) => {
+            // Without a rewritten block the removed fields cannot be left out
+            // alone, so the whole block goes, along with every kind it held
+            removed.extend(
+                parsed
+                    .fields()
+                    .filter(|field| !LAYOUT_TAGS.contains(&field.tag))
+                    .map(exif_kind),
+            );
+            if exif_thumbnail(&parsed).is_some() {
+                removed.push(MetadataKind::Thumbnail);
+            }
+            None
+        }
+    }
+}
+
+fn exif_kind(field: &Field) -> MetadataKind {
+    if field.ifd_num == In::THUMBNAIL {
+        return MetadataKind::Thumbnail;
+    }
+    match field.tag {
+        tag if tag.context() == Context::Gps => MetadataKind::Location,
+        Tag::BodySerialNumber | Tag::LensSerialNumber | Tag::MakerNote => {
+            MetadataKind::SerialNumbers
+        }
+        Tag::Artist | Tag::Copyright => MetadataKind::Copyright,
+        _ => MetadataKind::Other,
+    }
+}
+
+/// Returns the JPEG thumbnail stored in the second IFD of `exif`.
+fn exif_thumbnail(exif: &Exif) -> Option<&[u8]> {
+    let value = |tag| exif.get_field(tag, In::THUMBNAIL)?.value.get_uint(0);
+    let offset = value(Tag::JPEGInterchangeFormat)? as usize;
+    let length = value(Tag::JPEGInterchangeFormatLength)? as usize;
+    exif.buf().get(offset..offset.checked_add(length)?)
+}
+
+/// Rewrites `xmp` without the properties `policy` removes, or returns
+/// `None` if no property is left.
+fn filter_xmp(
+    xmp: Vec<u8>,
+    policy: &MetadataPolicy,
+    removed: &mut Vec<MetadataKind>,
+) -> Option<Vec<u8>> {
+    // Kinds are only reported once the whole packet could be parsed
+    let mut kinds = Vec::new();
+    match rewrite_xmp(&xmp, |name| keep(xmp_kind(name), policy, &mut kinds)) {
+        Ok(filtered) => {
+            removed.append(&mut kinds);
+            filtered
+        }
+        Err(_) => keep_opaque(policy, removed).then_some(xmp),
+    }
+}
+
+/// Rewrites `xmp` with only the properties for whose name, without the
+/// namespace prefix, `keep_property` returns `true`, or returns `None` if no
+/// property is left.
+fn rewrite_xmp(
+    xmp: &[u8],
+    mut keep_property: impl FnMut(&[u8]) -> bool,
+) -> Result<Option<Vec<u8>>, quick_xml::Error> {
+    let mut reader = XmlReader::from_reader(xmp);
+    let mut writer = XmlWriter::new(Vec::new());
+    // Whether each open element is an rdf:Description, whose children are properties
+    let mut descriptions = Vec::new();
+    let mut skipped_depth = 0usize;
+    let mut kept_any = false;
+
+    loop {
+        let event = reader.read_event()?;
+        if skipped_depth > 0 {
+            match event {
+                Event::Start(_) => skipped_depth += 1,
+                Event::End(_) => skipped_depth -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Eof => break,
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let is_start = matches!(event, Event::Start(_));
+                if descriptions.last() == Some(&true) {
+                    if !keep_property(element.local_name().as_ref()) {
+                        if is_start {
+                            skipped_depth = 1;
+                        }
+                        continue;
+                    }
+                    kept_any = true;
+                }
+
+                let is_description = element.name().as_ref() == b"rdf:Description";
+                let element = if is_description {
+                    // Simple properties may also be written as attributes
+                    filter_xmp_attributes(element, &mut keep_property, &mut kept_any)?
+                } else {
+                    element.to_owned()
+                };
+                if is_start {
+                    descriptions.push(is_description);
+                    writer.write_event(Event::Start(element))?;
+                } else {
+                    writer.write_event(Event::Empty(element))?;
+                }
+            }
+            Event::End(_) => {
+                descriptions.pop();
+                writer.write_event(event)?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(kept_any.then(|| writer.into_inner()))
+}
+
+fn filter_xmp_attributes(
+    element: &BytesStart,
+    keep_property: &mut impl FnMut(&[u8]) -> bool,
+    kept_any: &mut bool,
+) -> Result<BytesStart<'static>, quick_xml::Error> {
+    let mut filtered = element.to_owned();
+    filtered.clear_attributes();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let key = attribute.key;
+        let is_property = key.as_namespace_binding().is_none()
+            && key.prefix().is_none_or(|prefix| prefix.as_ref() != b"rdf");
+        if is_property {
+            if !keep_property(key.local_name().as_ref()) {
+                continue;
+            }
+            *kept_any = true;
+        }
+        filtered.push_attribute(attribute);
+    }
+    Ok(filtered)
+}
+
+/// Kind of an XMP property, by its name without the namespace prefix.
+fn xmp_kind(name: &[u8]) -> MetadataKind {
+    match name {
+        name if name.starts_with(b"GPS") => MetadataKind::Location,
+        b"Location" | b"LocationCreated" | b"LocationShown" | b"Sublocation" | b"City"
+        | b"State" | b"Country" | b"CountryCode" => MetadataKind::Location,
+        b"SerialNumber" | b"BodySerialNumber" | b"LensSerialNumber" | b"CameraSerialNumber" => {
+            MetadataKind::SerialNumbers
+        }
+        b"Thumbnails" => MetadataKind::Thumbnail,
+        b"creator"
+        | b"rights"
+        | b"Artist"
+        | b"Copyright"
+        | b"Owner"
+        | b"Marked"
+        | b"UsageTerms"
+        | b"WebStatement"
+        | b"Credit"
+        | b"CreatorContactInfo" => MetadataKind::Copyright,
+        _ => MetadataKind::Other,
+    }
+}
+
+/// Keyword of a PNG text chunk, which all three chunk types start with.
+fn text_keyword(contents: &[u8]) -> &[u8] {
+    contents.split(|&byte| byte == 0).next().unwrap_or_default()
+}
+
+/// Returns the XMP packet of a PNG `iTXt` chunk, inflating it if it is
+/// compressed, or `None` if the chunk holds other text or cannot be read.
+fn itxt_xmp(contents: &[u8]) -> Option<Vec<u8>> {
+    let rest = contents.strip_prefix(XMP_KEYWORD)?.strip_prefix(b"\0")?;
+    let (&[compressed, _method], rest) = rest.split_first_chunk::<2>()?;
+    // Skip the language tag and translated keyword
+    let mut parts = rest.splitn(3, |&byte| byte == 0);
+    parts.next()?;
+    parts.next()?;
+    let text = parts.next()?;
+    match compressed {
+        0 => Some(text.to_vec()),
+        _ => miniz_oxide::inflate::decompress_to_vec_zlib(text).ok(),
+    }
+}
+
+/// Sets the orientation in the first IFD of `exif` to 1 (upright).
+fn reset_orientation(exif: &mut [u8]) {
+    let big_endian = match exif.get(..4) {
+        Some(b"MM\0*") => true,
+        Some(b"II*\0") => false,
+        _ => return,
+    };
+    let read_u16 = |exif: &[u8], at: usize| {
+        let bytes = exif.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let Some(ifd) = exif
+        .get(4..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|bytes| {
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        })
+    else {
+        return;
+    };
+    let ifd = ifd as usize;
+    let Some(count) = read_u16(exif, ifd) else {
+        return;
+    };
+
+    for index in 0..usize::from(count) {
+        let entry = ifd + 2 + index * 12;
+        if read_u16(exif, entry) == Some(ORIENTATION_TAG) {
+            // Orientation is a single SHORT stored in the value field
+            if read_u16(exif, entry + 2) == Some(3)
+                && let Some(value) = exif.get_mut(entry + 8..entry + 10)
+            {
+                let upright = if big_endian {
+                    1u16.to_be_bytes()
+                } else {
+                    1u16.to_le_bytes()
+                };
+                value.copy_from_slice(&upright);
+            }
+            return;
+        }
+    }
+}
+
+fn container_error(format: &'static str) -> impl Fn(img_parts::Error) -> PixelGuardError {
+    move |e| PixelGuardError::encode(format, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::Value;
+
+    fn field(tag: Tag, value: Value) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        }
+    }
+
+    /// EXIF data turned by 90 degrees, with a location, a serial number, an
+    /// artist and a camera make.
+    fn sample_exif() -> Vec<u8> {
+        let fields = [
+            field(Tag::Orientation, Value::Short(vec![6])),
+            field(Tag::Make, Value::Ascii(vec![b"Camera".to_vec()])),
+            field(Tag::Artist, Value::Ascii(vec![b"Someone".to_vec()])),
+            field(Tag::BodySerialNumber, Value::Ascii(vec![b"12345".to_vec()])),
+            field(Tag::GPSLatitudeRef, Value::Ascii(vec![b"N".to_vec()])),
+        ];
+        let mut writer = ExifWriter::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+        exif.into_inner()
+    }
+
+    fn tags(exif: &[u8]) -> Vec<Tag> {
+        let parsed = ExifReader::new().read_raw(exif.to_vec()).unwrap();
+        parsed
+            .fields()
+            .map(|field| field.tag)
+            .filter(|tag| !LAYOUT_TAGS.contains(tag))
+            .collect()
+    }
+
+    fn orientation(exif: &[u8]) -> Option<u32> {
+        let parsed = ExifReader::new().read_raw(exif.to_vec()).unwrap();
+        parsed
+            .get_field(Tag::Orientation, In::PRIMARY)?
+            .value
+            .get_uint(0)
+    }
+
+    /// XMP packet holding a location and a creator.
+    const GPS_XMP: &[u8] = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/" xmlns:dc="http://purl.org/dc/elements/1.1/" exif:GPSLatitude="48,51.0N" exif:GPSLongitude="2,21.0E"><dc:creator>Someone</dc:creator></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+    /// Reads the metadata of a 16x16 PNG holding `chunk` before its end.
+    fn read_png_with(chunk: PngChunk) -> Metadata {
+        let mut encoded = Cursor::new(Vec::new());
+        image::RgbImage::new(16, 16)
+            .write_to(&mut encoded, image::ImageFormat::Png)
+            .unwrap();
+        let mut png = Png::from_bytes(encoded.into_inner().into()).unwrap();
+        let end = png.chunks().len() - 1;
+        png.chunks_mut().insert(end, chunk);
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("gps.png");
+        std::fs::write(&path, png.encoder().bytes()).unwrap();
+        Metadata::read(&crate::file::FileManager::analyze_file(path).unwrap()).unwrap()
+    }
+
+    /// Compressed `iTXt` chunk with the XMP keyword holding `data`.
+    fn compressed_xmp_chunk(data: &[u8]) -> PngChunk {
+        let contents = [XMP_KEYWORD, b"\0\x01\0\0\0", data].concat();
+        PngChunk::new(*b"iTXt", Bytes::from(contents))
+    }
+
+    /// Encodes a 16x16 JPEG holding `segments` after its JFIF header.
+    fn jpeg_with(segments: impl IntoIterator<Item = (u8, Vec<u8>)>) -> Vec<u8> {
+        let mut encoded = Cursor::new(Vec::new());
+        image::RgbImage::new(16, 16)
+            .write_to(&mut encoded, image::ImageFormat::Jpeg)
+            .unwrap();
+        let mut jpeg = Jpeg::from_bytes(encoded.into_inner().into()).unwrap();
+        let segments = segments.into_iter().map(|(marker, contents)| {
+            JpegSegment::new_with_contents(marker, Bytes::from(contents))
+        });
+        jpeg.segments_mut().splice(1..1, segments);
+        jpeg.encoder().bytes().to_vec()
+    }
+
+    fn read_jpeg(data: &[u8]) -> Metadata {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("photo.jpg");
+        std::fs::write(&path, data).unwrap();
+        Metadata::read(&crate::file::FileManager::analyze_file(path).unwrap()).unwrap()
+    }
+
+    fn markers_of(data: Vec<u8>) -> Vec<u8> {
+        let jpeg = Jpeg::from_bytes(data.into()).unwrap();
+        jpeg.segments().iter().map(JpegSegment::marker).collect()
+    }
+
+    /// A JPEG with XMP holding a location, extended XMP, IPTC and a comment.
+    fn annotated_jpeg() -> Vec<u8> {
+        jpeg_with([
+            (markers::APP1, [XMP_PREFIX, GPS_XMP].concat()),
+            (
+                markers::APP1,
+                b"http://ns.adobe.com/xmp/extension/\0guid".to_vec(),
+            ),
+            (markers::APP13, b"Photoshop 3.0\08BIM".to_vec()),
+            (markers::COM, b"A comment".to_vec()),
+        ])
+    }
+
+    #[test]
+    fn keep_all_carries_iptc_comments_and_extended_xmp() {
+        let (metadata, removed) = read_jpeg(&annotated_jpeg()).filter(&MetadataPolicy::keep_all());
+        assert!(removed.is_empty());
+        assert_eq!(metadata.jpeg_segments.len(), 3);
+
+        let markers = markers_of(metadata.embed_jpeg(jpeg_with([])).unwrap());
+        assert!(markers.contains(&markers::APP13));
+        assert!(markers.contains(&markers::COM));
+        assert_eq!(
+            markers
+                .iter()
+                .filter(|&&marker| marker == markers::APP1)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn privacy_reports_and_removes_iptc_but_keeps_comments() {
+        let jpeg = annotated_jpeg();
+        let (metadata, removed) = read_jpeg(&jpeg).filter(&MetadataPolicy::privacy());
+        assert_eq!(removed, [MetadataKind::Location, MetadataKind::Other]);
+
+        let repackaged = metadata.replace_in_jpeg(jpeg, &removed).unwrap();
+        let metadata = read_jpeg(&repackaged);
+        assert!(
+            !String::from_utf8(metadata.xmp.unwrap())
+                .unwrap()
+                .contains("GPS")
+        );
+        let markers: Vec<_> = metadata
+            .jpeg_segments
+            .iter()
+            .map(JpegSegment::marker)
+            .collect();
+        assert_eq!(markers, [markers::COM]);
+    }
+
+    #[test]
+    fn privacy_removes_location_from_compressed_png_xmp() {
+        let xmp = miniz_oxide::deflate::compress_to_vec_zlib(GPS_XMP, 6);
+        let metadata = read_png_with(compressed_xmp_chunk(&xmp));
+        assert!(metadata.png_text.is_empty());
+
+        let (metadata, removed) = metadata.filter(&MetadataPolicy::privacy());
+        let xmp = String::from_utf8(metadata.xmp.unwrap()).unwrap();
+        assert!(!xmp.contains("GPS"));
+        assert!(xmp.contains("Someone"));
+        assert_eq!(removed, [MetadataKind::Location]);
+    }
+
+    #[test]
+    fn unreadable_png_xmp_is_removed() {
+        let metadata = read_png_with(compressed_xmp_chunk(b"not zlib data"));
+        let (metadata, removed) = metadata.filter(&MetadataPolicy::privacy());
+        assert!(metadata.xmp.is_none());
+        assert!(metadata.png_text.is_empty());
+        assert_eq!(removed, [MetadataKind::Other]);
+    }
+
+    #[test]
+    fn privacy_removes_location_and_serial_numbers() {
+        let metadata = Metadata {
+            exif: Some(sample_exif()),
+            ..Metadata::default()
+        };
+        let (metadata, removed) = metadata.filter(&MetadataPolicy::privacy());
+
+        let tags = tags(&metadata.exif.unwrap());
+        assert!(!tags.contains(&Tag::GPSLatitudeRef));
+        assert!(!tags.contains(&Tag::BodySerialNumber));
+        assert!(tags.contains(&Tag::Artist));
+        assert!(tags.contains(&Tag::Make));
+        assert_eq!(
+            removed,
+            [MetadataKind::Location, MetadataKind::SerialNumbers]
+        );
+    }
+
+    #[test]
+    fn strip_all_reports_every_removed_kind_and_keeps_orientation() {
+        let metadata = Metadata {
+            exif: Some(sample_exif()),
+            icc: Some(vec![0; 16]),
+            ..Metadata::default()
+        };
+        let (metadata, removed) = metadata.filter(&MetadataPolicy::strip_all());
+
+        assert!(metadata.icc.is_none());
+        assert_eq!(tags(metadata.exif.as_ref().unwrap()), [Tag::Orientation]);
+        assert_eq!(
+            removed,
+            [
+                MetadataKind::Location,
+                MetadataKind::SerialNumbers,
+                MetadataKind::Copyright,
+                MetadataKind::ColorProfile,
+                MetadataKind::Other,
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_all_removes_nothing() {
+        let exif = sample_exif();
+        let metadata = Metadata {
+            exif: Some(exif.clone()),
+            ..Metadata::default()
+        };
+        let (metadata, removed) = metadata.filter(&MetadataPolicy::keep_all());
+
+        assert!(removed.is_empty());
+        assert_eq!(tags(&metadata.exif.unwrap()), tags(&exif));
+    }
+
+    #[test]
+    fn upright_resets_orientation() {
+        let metadata = Metadata {
+            exif: Some(sample_exif()),
+            ..Metadata::default()
+        };
+        let exif = metadata.upright().exif.unwrap();
+
+        assert_eq!(orientation(&exif), Some(1));
+        assert!(tags(&exif).contains(&Tag::Artist));
+    }
+
+    #[test]
+    fn upright_removes_xmp_orientation() {
+        let attribute = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:tiff="http://ns.adobe.com/tiff/1.0/" xmlns:dc="http://purl.org/dc/elements/1.1/" tiff:Orientation="6"><dc:creator>Someone</dc:creator></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        let element = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:tiff="http://ns.adobe.com/tiff/1.0/" xmlns:dc="http://purl.org/dc/elements/1.1/"><tiff:Orientation>6</tiff:Orientation><dc:creator>Someone</dc:creator></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        for xmp in [&attribute[..], &element[..]] {
+            let metadata = Metadata {
+                xmp: Some(xmp.to_vec()),
+                ..Metadata::default()
+            };
+            let xmp = String::from_utf8(metadata.upright().xmp.unwrap()).unwrap();
+            assert!(!xmp.contains("Orientation"), "{xmp}");
+            assert!(xmp.contains("Someone"));
+        }
+
+        let only_orientation = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:tiff="http://ns.adobe.com/tiff/1.0/" tiff:Orientation="6"/></rdf:RDF></x:xmpmeta>"#;
+        let metadata = Metadata {
+            xmp: Some(only_orientation.to_vec()),
+            ..Metadata::default()
+        };
+        assert!(metadata.upright().xmp.is_none());
+    }
+
+    #[test]
+    fn upright_drops_exif_holding_only_orientation() {
+        let (metadata, _) = Metadata {
+            exif: Some(sample_exif()),
+            ..Metadata::default()
+        }
+        .filter(&MetadataPolicy::strip_all());
+
+        assert!(metadata.upright().exif.is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use image::imageops::FilterType;
+use image::{Rgba, RgbaImage};
+use rgb::FromSlice;
+use std::fmt;
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+
+/// How closely a compressed image matches its input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityMetrics {
+    /// Structural similarity of the brightness, 1.0 for identical images.
+    pub ssim: f64,
+    /// Peak signal-to-noise ratio of the color channels in dB, infinite for
+    /// identical images. Above about 40 dB differences are hard to see.
+    pub psnr: f64,
+    /// DSSIM perceptual distance in Lab color, 0.0 for identical images.
+    /// Below about 0.001 differences are hard to see. `None` if the image is
+    /// too small to measure it.
+    pub dssim: Option<f64>,
+}
+
+impl fmt::Display for QualityMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SSIM {:.4}, ", self.ssim)?;
+        if self.psnr.is_finite() {
+            write!(f, "PSNR {:.1} dB, ", self.psnr)?;
+        } else {
+            write!(f, "PSNR lossless, ")?;
+        }
+        match self.dssim {
+            Some(dssim) => write!(f, "DSSIM {:.5}", dssim),
+            None => write!(f, "DSSIM unavailable"),
+        }
+    }
+}
+
+/// Compares `output` with the `input` it was encoded from. An input of a
+/// different size is first resized to match, so only encoding losses count.
+///
+/// Returns `None` if either image is empty.
+pub(crate) fn measure(input: &RgbaImage, output: &RgbaImage) -> Option<QualityMetrics> {
+    let (width, height) = output.dimensions();
+    if width == 0 || height == 0 || input.width() == 0 || input.height() == 0 {
+        return None;
+    }
+
+    let resized;
+    let input = if input.dimensions() == output.dimensions() {
+        input
+    } else {
+        resized = image::imageops::resize(input, width, height, FilterType::Lanczos3);
+        &resized
+    };
+
+    // Transparent pixels are compared as they would look on a white page
+    let input_rgb = flatten(input);
+    let output_rgb = flatten(output);
+
+    Some(QualityMetrics {
+        ssim: ssim(&luma(&input_rgb), &luma(&output_rgb), width, height),
+        psnr: psnr(&input_rgb, &output_rgb),
+        dssim: dssim(input, output),
+    })
+}
+
+fn flatten(image: &RgbaImage) -> Vec<[f64; 3]> {
+    image
+        .pixels()
+        .map(|&Rgba([r, g, b, a])| {
+            let alpha = f64::from(a) / 255.0;
+            let over_white = |channel: u8| f64::from(channel) * alpha + 255.0 * (1.0 - alpha);
+            [over_white(r), over_white(g), over_white(b)]
+        })
+        .collect()
+}
+
+fn luma(pixels: &[[f64; 3]]) -> Vec<f64> {
+    pixels
+        .iter()
+        .map(|[r, g, b]| 0.299 * r + 0.587 * g + 0.114 * b)
+        .collect()
+}
+
+fn psnr(input: &[[f64; 3]], output: &[[f64; 3]]) -> f64 {
+    let squared_error: f64 = input
+        .iter()
+        .zip(output)
+        .flat_map(|(a, b)| a.iter().zip(b).map(|(a, b)| (a - b).powi(2)))
+        .sum();
+    let mean_squared_error = squared_error / (input.len() * 3) as f64;
+    if mean_squared_error == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mean_squared_error).log10()
+    }
+}
+
+/// Mean SSIM over windows overlapping by half, or over the whole image if it
+/// is smaller than one window.
+fn ssim(input: &[f64], output: &[f64], width: u32, height: u32) -> f64 {
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    let step_x = (window_width / 2).max(1);
+    let step_y = (window_height / 2).max(1);
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            let indices = (y..y + window_height).flat_map(|row| {
+                (x..x + window_width).map(move |column| (row * width + column) as usize)
+            });
+            total += window_ssim(indices.map(|index| (input[index], output[index])));
+            windows += 1;
+            x += step_x;
+        }
+        y += step_y;
+    }
+    total / f64::from(windows)
+}
+
+fn window_ssim(pairs: impl Iterator<Item = (f64, f64)> + Clone) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let count = pairs.clone().count() as f64;
+    let (sum_a, sum_b) = pairs
+        .clone()
+        .fold((0.0, 0.0), |(sum_a, sum_b), (a, b)| (sum_a + a, sum_b + b));
+    let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+    let (variance_a, variance_b, covariance) =
+        pairs.fold((0.0, 0.0, 0.0), |(va, vb, cov), (a, b)| {
+            let (da, db) = (a - mean_a, b - mean_b);
+            (va + da * da, vb + db * db, cov + da * db)
+        });
+    let (variance_a, variance_b, covariance) =
+        (variance_a / count, variance_b / count, covariance / count);
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2))
+}
+
+/// DSSIM distance, or `None` if an image is too small for it.
+fn dssim(input: &RgbaImage, output: &RgbaImage) -> Option<f64> {
+    let (width, height) = (output.width() as usize, output.height() as usize);
+    let dssim = dssim_core::Dssim::new();
+    let input = dssim.create_image_rgba(input.as_raw().as_rgba(), width, height)?;
+    let output = dssim.create_image_rgba(output.as_raw().as_rgba(), width, height)?;
+    let (distance, _) = dssim.compare(&input, output);
+    Some(distance.into())
+}
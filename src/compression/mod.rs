@@ -1,7 +1,27 @@
+//! Compression settings, the compression engine and its results.
+
+mod animation;
+mod avif;
+mod backup;
+mod cancel;
 mod engine;
+mod jxl;
+mod metadata;
+mod metrics;
+mod resize;
 mod result;
 mod settings;
+mod template;
+mod variants;
 
+pub use backup::{BackupEntry, BackupManifest, MANIFEST_FILE_NAME};
+pub use cancel::CancellationToken;
 pub use engine::{CompressionEngine, CompressionProgress};
-pub use result::{CompressionResult, CompressionStatus};
-pub use settings::{CompressionSettings, OutputFormat};
+pub use metrics::QualityMetrics;
+pub use result::{CompressionResult, CompressionStatus, QualitySearch};
+pub use settings::{
+    AvifSettings, AvifSubsampling, BackupMode, CompressionSettings, ConflictPolicy, JpegXlSettings,
+    MetadataKind, MetadataPolicy, OutputFormat, OutputVariant, PngDeflater, PngFilter, PngSettings,
+    ResizeFilter, ResizeMode, ResizeSettings, VisualTarget, WebPMode, WebPSettings,
+};
+pub use template::{DEFAULT_FILENAME_TEMPLATE, FILENAME_TOKENS};
@@ -0,0 +1,213 @@
+use crate::compression::{ResizeFilter, ResizeMode, ResizeSettings};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+
+/// How an image is resized: scaled to `width` x `height`, then cropped to the
+/// `crop_width` x `crop_height` area centered in it.
+struct ResizePlan {
+    width: u32,
+    height: u32,
+    crop_width: u32,
+    crop_height: u32,
+}
+
+/// Returns `true` if resizing changes the size of a `width` x `height` image.
+pub(crate) fn changes_size(settings: &ResizeSettings, width: u32, height: u32) -> bool {
+    plan(settings, width, height).is_some()
+}
+
+/// Size of a `width` x `height` image after resizing.
+pub(crate) fn resized_size(settings: &ResizeSettings, width: u32, height: u32) -> (u32, u32) {
+    plan(settings, width, height)
+        .map_or((width, height), |plan| (plan.crop_width, plan.crop_height))
+}
+
+/// Resizes `img` as configured, or returns it unchanged if it already has
+/// the target size.
+pub(crate) fn resize(img: DynamicImage, settings: &ResizeSettings) -> DynamicImage {
+    let Some(plan) = plan(settings, img.width(), img.height()) else {
+        return img;
+    };
+    let scaled = if (plan.width, plan.height) == img.dimensions() {
+        img
+    } else {
+        img.resize_exact(plan.width, plan.height, filter_type(settings.filter))
+    };
+    if (plan.crop_width, plan.crop_height) == (plan.width, plan.height) {
+        return scaled;
+    }
+    scaled.crop_imm(
+        (plan.width - plan.crop_width) / 2,
+        (plan.height - plan.crop_height) / 2,
+        plan.crop_width,
+        plan.crop_height,
+    )
+}
+
+pub(crate) fn filter_type(filter: ResizeFilter) -> FilterType {
+    match filter {
+        ResizeFilter::Nearest => FilterType::Nearest,
+        ResizeFilter::Triangle => FilterType::Triangle,
+        ResizeFilter::CatmullRom => FilterType::CatmullRom,
+        ResizeFilter::Gaussian => FilterType::Gaussian,
+        ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+    }
+}
+
+/// Works out the resize of a `width` x `height` image, or `None` if its size
+/// stays the same.
+fn plan(settings: &ResizeSettings, width: u32, height: u32) -> Option<ResizePlan> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let (box_width, box_height) = (settings.max_width, settings.max_height);
+    let scale_x = f64::from(box_width) / f64::from(width);
+    let scale_y = f64::from(box_height) / f64::from(height);
+    let limit = |scale: f64| {
+        if settings.never_upscale {
+            scale.min(1.0)
+        } else {
+            scale
+        }
+    };
+    let scaled = |scale: f64| {
+        let side = |length: u32| ((f64::from(length) * scale).round() as u32).max(1);
+        (side(width), side(height))
+    };
+
+    let plan = match settings.mode {
+        ResizeMode::Fit => {
+            // A side of 0 does not limit the size
+            let scale = match (box_width, box_height) {
+                (0, 0) => return None,
+                (0, _) => scale_y,
+                (_, 0) => scale_x,
+                _ => scale_x.min(scale_y),
+            };
+            let (width, height) = scaled(limit(scale));
+            ResizePlan {
+                width,
+                height,
+                crop_width: width,
+                crop_height: height,
+            }
+        }
+        ResizeMode::Fill => {
+            let (width, height) = scaled(limit(scale_x.max(scale_y)));
+            ResizePlan {
+                width,
+                height,
+                crop_width: box_width.clamp(1, width),
+                crop_height: box_height.clamp(1, height),
+            }
+        }
+        ResizeMode::Exact => {
+            let (width, height) = if settings.never_upscale {
+                (box_width.min(width), box_height.min(height))
+            } else {
+                (box_width, box_height)
+            };
+            let (width, height) = (width.max(1), height.max(1));
+            ResizePlan {
+                width,
+                height,
+                crop_width: width,
+                crop_height: height,
+            }
+        }
+    };
+
+    if (plan.crop_width, plan.crop_height) == (width, height)
+        && (plan.width, plan.height) == (width, height)
+    {
+        None
+    } else {
+        Some(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: ResizeMode, max_width: u32, max_height: u32) -> ResizeSettings {
+        ResizeSettings {
+            max_width,
+            max_height,
+            mode,
+            ..ResizeSettings::default()
+        }
+    }
+
+    /// Size of a `width` x `height` image after resizing, or `None` if it
+    /// stays the same.
+    fn resized(settings: &ResizeSettings, width: u32, height: u32) -> Option<(u32, u32)> {
+        plan(settings, width, height).map(|plan| (plan.crop_width, plan.crop_height))
+    }
+
+    #[test]
+    fn fit_keeps_the_aspect_ratio_inside_the_box() {
+        let settings = settings(ResizeMode::Fit, 400, 400);
+        assert_eq!(resized(&settings, 800, 600), Some((400, 300)));
+        assert_eq!(resized(&settings, 600, 800), Some((300, 400)));
+    }
+
+    #[test]
+    fn fit_ignores_a_side_of_zero() {
+        assert_eq!(
+            resized(&settings(ResizeMode::Fit, 200, 0), 800, 600),
+            Some((200, 150))
+        );
+        assert_eq!(
+            resized(&settings(ResizeMode::Fit, 0, 300), 800, 600),
+            Some((400, 300))
+        );
+        assert_eq!(resized(&settings(ResizeMode::Fit, 0, 0), 800, 600), None);
+    }
+
+    #[test]
+    fn fill_covers_the_box_and_crops_the_overflow() {
+        let plan = plan(&settings(ResizeMode::Fill, 400, 400), 800, 600).unwrap();
+        assert_eq!((plan.width, plan.height), (533, 400));
+        assert_eq!((plan.crop_width, plan.crop_height), (400, 400));
+    }
+
+    #[test]
+    fn exact_stretches_to_the_box() {
+        assert_eq!(
+            resized(&settings(ResizeMode::Exact, 300, 300), 800, 600),
+            Some((300, 300))
+        );
+    }
+
+    #[test]
+    fn never_upscale_leaves_small_images_alone() {
+        for mode in [ResizeMode::Fit, ResizeMode::Fill, ResizeMode::Exact] {
+            assert!(!changes_size(&settings(mode, 1600, 1600), 800, 600));
+        }
+        // Fill still crops to the box where the image is larger
+        assert_eq!(
+            resized(&settings(ResizeMode::Fill, 1600, 400), 800, 600),
+            Some((800, 400))
+        );
+    }
+
+    #[test]
+    fn upscaling_enlarges_small_images() {
+        let settings = ResizeSettings {
+            never_upscale: false,
+            ..settings(ResizeMode::Fit, 1600, 1600)
+        };
+        assert_eq!(resized(&settings, 800, 600), Some((1600, 1200)));
+    }
+
+    #[test]
+    fn empty_images_are_not_resized() {
+        assert!(!changes_size(
+            &settings(ResizeMode::Exact, 100, 100),
+            0,
+            600
+        ));
+    }
+}
@@ -1,24 +1,69 @@
+use crate::compression::{MetadataKind, OutputFormat, QualityMetrics};
+use crate::error::PixelGuardError;
 use std::path::PathBuf;
 
+/// Outcome of compressing a single file.
 #[derive(Clone, Debug)]
 pub struct CompressionResult {
+    /// File that was read.
     pub input_path: PathBuf,
+    /// File that was written, empty if nothing was written.
     pub output_path: PathBuf,
+    /// Size of the input in bytes.
     pub original_size: u64,
+    /// Size of the output in bytes.
     pub compressed_size: u64,
+    /// Fraction of the original size that was saved, negative if the output grew.
     pub compression_ratio: f32,
+    /// Whether the file was compressed, failed or skipped.
     pub status: CompressionStatus,
+    /// Time spent compressing the file.
     pub processing_time: std::time::Duration,
+    /// Format of the output, `None` if nothing was written. Shows the format
+    /// [`OutputFormat::Auto`] picked.
+    pub output_format: Option<OutputFormat>,
+    /// Width and height of the output, `None` if nothing was written.
+    pub dimensions: Option<(u32, u32)>,
+    /// Quality picked automatically to meet a size or visual quality target, `None` if the
+    /// configured quality was used.
+    pub quality: Option<f32>,
+    /// Search that picked [`quality`](Self::quality), `None` if the configured quality was used.
+    pub quality_search: Option<QualitySearch>,
+    /// How closely the output matches the input, if
+    /// [`measure_quality`](crate::compression::CompressionSettings::measure_quality)
+    /// is on and the output format can be decoded.
+    pub metrics: Option<QualityMetrics>,
+    /// Metadata the [`MetadataPolicy`](crate::compression::MetadataPolicy) removed from the input, in the order
+    /// of [`MetadataKind`]. What the output format could not hold is not listed.
+    pub metadata_removed: Vec<MetadataKind>,
+    /// Manifest of the in-place batch that replaced the input, used to
+    /// restore the original with
+    /// [`CompressionEngine::restore_backup`](crate::compression::CompressionEngine::restore_backup).
+    pub backup_manifest: Option<PathBuf>,
 }
 
+/// How a [`CompressionResult::quality`] was picked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QualitySearch {
+    /// Highest quality whose output fits the file size limit.
+    SizeLimit,
+    /// Lowest quality whose output reaches the visual quality target.
+    VisualTarget,
+}
+
+/// Final state of a [`CompressionResult`].
 #[derive(Clone, Debug)]
 pub enum CompressionStatus {
+    /// The output was written.
     Success,
-    Failed(String),
+    /// Compression failed with the given error.
+    Failed(PixelGuardError),
+    /// The file was deliberately not compressed, for the given reason.
     Skipped(String),
 }
 
 impl CompressionResult {
+    /// Creates a successful result and computes its compression ratio.
     pub fn new(
         input_path: PathBuf,
         output_path: PathBuf,
@@ -40,10 +85,18 @@ impl CompressionResult {
             compression_ratio,
             status: CompressionStatus::Success,
             processing_time,
+            output_format: None,
+            dimensions: None,
+            quality: None,
+            quality_search: None,
+            metrics: None,
+            metadata_removed: Vec::new(),
+            backup_manifest: None,
         }
     }
 
-    pub fn failed(input_path: PathBuf, error: String) -> Self {
+    /// Creates a failed result for `input_path`.
+    pub fn failed(input_path: PathBuf, error: PixelGuardError) -> Self {
         Self {
             input_path,
             output_path: PathBuf::new(),
@@ -52,14 +105,38 @@ impl CompressionResult {
             compression_ratio: 0.0,
             status: CompressionStatus::Failed(error),
             processing_time: std::time::Duration::from_secs(0),
+            output_format: None,
+            dimensions: None,
+            quality: None,
+            quality_search: None,
+            metrics: None,
+            metadata_removed: Vec::new(),
+            backup_manifest: None,
         }
     }
 
-    pub fn space_saved_bytes(&self) -> u64 {
-        if self.original_size > self.compressed_size {
-            self.original_size - self.compressed_size
-        } else {
-            0
+    /// Creates a result for a file that was deliberately not compressed.
+    pub fn skipped(input_path: PathBuf, reason: String) -> Self {
+        Self {
+            input_path,
+            output_path: PathBuf::new(),
+            original_size: 0,
+            compressed_size: 0,
+            compression_ratio: 0.0,
+            status: CompressionStatus::Skipped(reason),
+            processing_time: std::time::Duration::from_secs(0),
+            output_format: None,
+            dimensions: None,
+            quality: None,
+            quality_search: None,
+            metrics: None,
+            metadata_removed: Vec::new(),
+            backup_manifest: None,
         }
     }
+
+    /// Number of bytes saved, zero if the output grew.
+    pub fn space_saved_bytes(&self) -> u64 {
+        self.original_size.saturating_sub(self.compressed_size)
+    }
 }
@@ -1,18 +1,573 @@
+use crate::compression::QualityMetrics;
+use crate::compression::template::{self, DEFAULT_FILENAME_TEMPLATE};
+use crate::error::PixelGuardError;
+
+/// Image format written by the compression engine.
 #[derive(Clone, Debug, PartialEq)]
 pub enum OutputFormat {
+    /// Lossless PNG, optimized with oxipng. Animations are written as APNG.
     Png,
+    /// WebP, lossy or lossless depending on [`WebPSettings::mode`].
     WebP,
+    /// Baseline JPEG.
     Jpeg,
+    /// AVIF (AV1 still image), encoded with rav1e.
+    Avif,
+    /// JPEG XL, encoded with libjxl's `cjxl` tool.
+    JpegXl,
+    /// GIF with a 256 color palette per frame.
+    Gif,
+    /// Tries PNG, WebP, JPEG, AVIF and JPEG XL with their current settings
+    /// and keeps whichever output is smallest, per file.
+    Auto,
+}
+
+impl OutputFormat {
+    /// Lowercase name of the format, e.g. `jpeg`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Jpeg => "jpeg",
+            Self::Avif => "avif",
+            Self::JpegXl => "jxl",
+            Self::Gif => "gif",
+            Self::Auto => "auto",
+        }
+    }
+
+    /// File extension written for the format, without the dot. [`Auto`](Self::Auto)
+    /// outputs get the extension of the format picked for them.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            _ => self.name(),
+        }
+    }
+
+    /// MIME type of the format, e.g. `image/webp`.
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Jpeg => "image/jpeg",
+            Self::Avif => "image/avif",
+            Self::JpegXl => "image/jxl",
+            Self::Gif => "image/gif",
+            Self::Auto => "image/*",
+        }
+    }
+
+    /// Returns `true` if animated inputs keep all their frames in this format.
+    /// Other formats only store the first frame.
+    pub fn supports_animation(&self) -> bool {
+        matches!(self, Self::Png | Self::WebP | Self::Gif)
+    }
+}
+
+/// What happens when an output file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Leave the existing file alone and report the input as skipped.
+    Skip,
+    /// Write to the first free name with a numbered suffix, e.g. `logo-1.png`.
+    Rename,
+    /// Leave the existing file alone and report the input as failed.
+    Fail,
+}
+
+/// Where in-place compression keeps the originals it replaces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackupMode {
+    /// Keep no copy. Replaced originals cannot be restored.
+    None,
+    /// Copy originals into a new folder inside
+    /// [`backup_directory`](CompressionSettings::backup_directory) for each batch.
+    #[default]
+    Folder,
+    /// Move originals to the system trash.
+    Trash,
+}
+
+/// Visual quality every output must reach when
+/// [`match_visual_quality`](CompressionSettings::match_visual_quality) is on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VisualTarget {
+    /// SSIM of at least the given value, 0.0-1.0.
+    MinSsim(f64),
+    /// DSSIM perceptual distance of at most the given value.
+    MaxDssim(f64),
+}
+
+impl Default for VisualTarget {
+    fn default() -> Self {
+        Self::MinSsim(0.98)
+    }
+}
+
+impl VisualTarget {
+    /// Returns `true` if an output with these `metrics` looks good enough. A
+    /// target whose metric could not be measured is never met.
+    pub fn is_met_by(&self, metrics: &QualityMetrics) -> bool {
+        match *self {
+            Self::MinSsim(ssim) => metrics.ssim >= ssim,
+            Self::MaxDssim(dssim) => metrics.dssim.is_some_and(|measured| measured <= dssim),
+        }
+    }
+}
+
+/// Row filter strategy oxipng can try on a PNG.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngFilter {
+    /// No filtering.
+    None,
+    /// Difference to the pixel on the left.
+    Sub,
+    /// Difference to the pixel above.
+    Up,
+    /// Difference to the average of the left and upper pixels.
+    Average,
+    /// Paeth predictor.
+    Paeth,
+    /// Per-row choice minimising the sum of absolute differences.
+    MinSum,
+    /// Per-row choice minimising entropy.
+    Entropy,
+    /// Per-row choice minimising distinct byte pairs.
+    Bigrams,
+    /// Per-row choice minimising the entropy of byte pairs.
+    BigEnt,
+    /// Per-row choice by trial compression. Very slow.
+    Brute,
+}
+
+impl PngFilter {
+    /// Every filter, in the order oxipng numbers them.
+    pub const ALL: [PngFilter; 10] = [
+        PngFilter::None,
+        PngFilter::Sub,
+        PngFilter::Up,
+        PngFilter::Average,
+        PngFilter::Paeth,
+        PngFilter::MinSum,
+        PngFilter::Entropy,
+        PngFilter::Bigrams,
+        PngFilter::BigEnt,
+        PngFilter::Brute,
+    ];
+}
+
+/// DEFLATE implementation used for PNG image data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PngDeflater {
+    /// libdeflate at the compression level of the selected preset.
+    Libdeflater,
+    /// Zopfli, which is much slower but usually a few percent smaller.
+    Zopfli {
+        /// Number of compression iterations, 1-255.
+        iterations: u8,
+    },
+}
+
+/// Advanced oxipng options applied on top of the `png_level` preset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PngSettings {
+    /// Filter strategies to try. Empty uses the preset's selection.
+    pub filters: Vec<PngFilter>,
+    /// DEFLATE implementation.
+    pub deflater: PngDeflater,
+    /// Write Adam7 interlaced images, which render progressively but are larger.
+    pub interlace: bool,
+    /// Try reducing the bit depth.
+    pub bit_depth_reduction: bool,
+    /// Try reducing the color type, e.g. RGBA to RGB or palette.
+    pub color_type_reduction: bool,
+    /// Try removing unused and duplicate palette entries.
+    pub palette_reduction: bool,
+    /// Try converting color images with only gray pixels to grayscale.
+    pub grayscale_reduction: bool,
+}
+
+impl Default for PngSettings {
+    fn default() -> Self {
+        Self {
+            filters: Vec::new(),
+            deflater: PngDeflater::Libdeflater,
+            interlace: false,
+            bit_depth_reduction: true,
+            color_type_reduction: true,
+            palette_reduction: true,
+            grayscale_reduction: true,
+        }
+    }
+}
+
+/// How WebP output is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebPMode {
+    /// Lossy VP8 encoding at `webp_quality`.
+    Lossy,
+    /// Lossless VP8L encoding. Decoded pixels match the input exactly.
+    Lossless,
+    /// Lossless encoding after slightly adjusting pixel values, see
+    /// [`WebPSettings::near_lossless`].
+    NearLossless,
+}
+
+/// WebP encoder options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebPSettings {
+    /// Lossy, lossless or near-lossless encoding.
+    pub mode: WebPMode,
+    /// Encoder effort, 0 (fastest) to 6 (smallest output).
+    pub method: u8,
+    /// Amount of preprocessing in near-lossless mode, 0 (most) to 100 (none).
+    pub near_lossless: u8,
+}
+
+impl Default for WebPSettings {
+    fn default() -> Self {
+        Self {
+            mode: WebPMode::Lossy,
+            method: 4,
+            near_lossless: 60,
+        }
+    }
+}
+
+/// Chroma subsampling used for AVIF output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AvifSubsampling {
+    /// Full resolution color. Best for text, line art and sharp color edges.
+    Yuv444,
+    /// Color at half horizontal resolution.
+    Yuv422,
+    /// Color at half resolution in both directions. Smallest, fine for photos.
+    Yuv420,
+}
+
+/// AVIF encoder options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvifSettings {
+    /// Encoder speed, 1 (slowest, smallest) to 10 (fastest).
+    pub speed: u8,
+    /// Chroma subsampling of the color planes.
+    pub subsampling: AvifSubsampling,
+}
+
+impl Default for AvifSettings {
+    fn default() -> Self {
+        Self {
+            speed: 6,
+            subsampling: AvifSubsampling::Yuv420,
+        }
+    }
+}
+
+/// JPEG XL encoder options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JpegXlSettings {
+    /// Encoder effort, 1 (fastest) to 9 (smallest output).
+    pub effort: u8,
+    /// Losslessly repackage JPEG inputs instead of re-encoding their pixels.
+    /// The original JPEG can be reconstructed bit for bit with `djxl`, unless
+    /// [`metadata`](CompressionSettings::metadata) strips some of its metadata.
+    pub lossless_jpeg: bool,
+}
+
+impl Default for JpegXlSettings {
+    fn default() -> Self {
+        Self {
+            effort: 7,
+            lossless_jpeg: true,
+        }
+    }
+}
+
+/// How images are fitted to [`ResizeSettings::max_width`] and
+/// [`ResizeSettings::max_height`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Scale to fit inside the box, keeping the aspect ratio. A side of 0
+    /// is not limited.
+    #[default]
+    Fit,
+    /// Scale to cover the box, keeping the aspect ratio, and crop the
+    /// overflow evenly from both sides.
+    Fill,
+    /// Stretch to exactly the box.
+    Exact,
+}
+
+/// Resampling filter used when resizing, from fastest to sharpest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    /// Nearest neighbor. Keeps pixel art crisp.
+    Nearest,
+    /// Bilinear.
+    Triangle,
+    /// Bicubic Catmull-Rom spline.
+    CatmullRom,
+    /// Gaussian blur, soft but free of ringing.
+    Gaussian,
+    /// Lanczos with a window of 3. Sharpest, best for photos.
+    #[default]
+    Lanczos3,
+}
+
+/// Resize stage applied before encoding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResizeSettings {
+    /// Width of the box images are resized to, in pixels.
+    pub max_width: u32,
+    /// Height of the box images are resized to, in pixels.
+    pub max_height: u32,
+    /// How images are fitted to the box.
+    pub mode: ResizeMode,
+    /// Leave images smaller than the box at their size instead of enlarging them.
+    pub never_upscale: bool,
+    /// Resampling filter.
+    pub filter: ResizeFilter,
 }
 
+impl Default for ResizeSettings {
+    fn default() -> Self {
+        Self {
+            max_width: 1600,
+            max_height: 1600,
+            mode: ResizeMode::default(),
+            never_upscale: true,
+            filter: ResizeFilter::default(),
+        }
+    }
+}
+
+/// One of several outputs written for every input, e.g. for a `srcset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputVariant {
+    /// Width the image is scaled down to, keeping its aspect ratio, in place
+    /// of the resize stage. 0 keeps the size the resize stage produces.
+    pub width: u32,
+    /// Format the variant is written in.
+    pub format: OutputFormat,
+}
+
+/// Category of metadata a [`MetadataPolicy`] keeps or removes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MetadataKind {
+    /// GPS positions and place names.
+    Location,
+    /// Camera and lens serial numbers, and maker notes that may hold them.
+    SerialNumbers,
+    /// Embedded preview images.
+    Thumbnail,
+    /// Author and copyright notices.
+    Copyright,
+    /// ICC color profile.
+    ColorProfile,
+    /// Everything else: camera settings, dates, descriptions, software, and
+    /// blocks that cannot be looked into.
+    Other,
+}
+
+impl MetadataKind {
+    /// Short lowercase description, used in reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            MetadataKind::Location => "GPS location",
+            MetadataKind::SerialNumbers => "serial numbers",
+            MetadataKind::Thumbnail => "thumbnail",
+            MetadataKind::Copyright => "author and copyright",
+            MetadataKind::ColorProfile => "ICC profile",
+            MetadataKind::Other => "other metadata",
+        }
+    }
+}
+
+/// Which EXIF, XMP, ICC and PNG text metadata is carried from each input into
+/// its output. The output format may still drop what it cannot hold: AVIF
+/// keeps EXIF only, GIF nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetadataPolicy {
+    /// Remove all metadata apart from what
+    /// [`keep_icc`](Self::keep_icc) and [`keep_copyright`](Self::keep_copyright)
+    /// hold on to.
+    pub strip_all: bool,
+    /// Keep ICC color profiles.
+    pub keep_icc: bool,
+    /// Keep author and copyright notices.
+    pub keep_copyright: bool,
+    /// Remove GPS positions and place names.
+    pub strip_gps: bool,
+    /// Remove camera and lens serial numbers, and the maker notes that may
+    /// hold them.
+    pub strip_serial_numbers: bool,
+    /// Remove embedded preview thumbnails.
+    pub strip_thumbnails: bool,
+}
+
+impl MetadataPolicy {
+    /// Removes everything, including color profiles.
+    pub fn strip_all() -> Self {
+        Self {
+            strip_all: true,
+            keep_icc: false,
+            keep_copyright: false,
+            strip_gps: true,
+            strip_serial_numbers: true,
+            strip_thumbnails: true,
+        }
+    }
+
+    /// Keeps everything the output format can hold.
+    pub fn keep_all() -> Self {
+        Self {
+            strip_all: false,
+            keep_icc: true,
+            keep_copyright: true,
+            strip_gps: false,
+            strip_serial_numbers: false,
+            strip_thumbnails: false,
+        }
+    }
+
+    /// Keeps color profiles, credits and camera settings, but removes what
+    /// identifies where a photo was taken or by which device.
+    pub fn privacy() -> Self {
+        Self {
+            strip_gps: true,
+            strip_serial_numbers: true,
+            strip_thumbnails: true,
+            ..Self::keep_all()
+        }
+    }
+
+    /// Returns `true` if metadata of `kind` is removed.
+    pub fn removes(&self, kind: MetadataKind) -> bool {
+        match kind {
+            MetadataKind::Location => self.strip_all || self.strip_gps,
+            MetadataKind::SerialNumbers => self.strip_all || self.strip_serial_numbers,
+            MetadataKind::Thumbnail => self.strip_all || self.strip_thumbnails,
+            MetadataKind::Copyright => !self.keep_copyright,
+            MetadataKind::ColorProfile => !self.keep_icc,
+            MetadataKind::Other => self.strip_all,
+        }
+    }
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        Self::strip_all()
+    }
+}
+
+/// Options controlling how a batch of images is compressed.
 #[derive(Clone, Debug)]
 pub struct CompressionSettings {
+    /// Format every input is converted to.
     pub output_format: OutputFormat,
-    pub png_level: u8,     // 1-6
-    pub webp_quality: f32, // 0.0-100.0
-    pub jpeg_quality: u8,  // 1-100
-    pub preserve_metadata: bool,
+    /// PNG optimization level, 1-6, mapped onto the oxipng presets.
+    pub png_level: u8,
+    /// Advanced PNG optimization options.
+    pub png: PngSettings,
+    /// WebP encoder quality for lossy mode, 0.0-100.0.
+    pub webp_quality: f32,
+    /// WebP encoding mode and effort.
+    pub webp: WebPSettings,
+    /// JPEG encoder quality, 1-100.
+    pub jpeg_quality: u8,
+    /// AVIF encoder quality, 1.0-100.0.
+    pub avif_quality: f32,
+    /// AVIF encoder speed and chroma subsampling.
+    pub avif: AvifSettings,
+    /// JPEG XL quality for re-encoded images, 0.0-100.0 where 100 is lossless.
+    pub jxl_quality: f32,
+    /// JPEG XL effort and JPEG transcoding.
+    pub jxl: JpegXlSettings,
+    /// Resize every image with [`resize`](Self::resize) before encoding it.
+    /// JPEG inputs that change size are re-encoded instead of losslessly
+    /// repackaged as JPEG XL.
+    pub resize_images: bool,
+    /// Target size, mode and filter of the resize stage.
+    pub resize: ResizeSettings,
+    /// Write every entry of [`variants`](Self::variants) for each input
+    /// instead of a single output. Unless the file name template contains
+    /// `{width}`, `-{width}w` is added to the stem so the variants get
+    /// distinct names.
+    pub generate_variants: bool,
+    /// Widths and formats written when
+    /// [`generate_variants`](Self::generate_variants) is on. Images are never
+    /// enlarged, so widths above the image's width collapse into one variant.
+    pub variants: Vec<OutputVariant>,
+    /// Write a `<picture>` element listing each input's variants into the
+    /// folder holding them all, as `<stem>.html`.
+    pub write_picture_html: bool,
+    /// Write a JSON manifest of each input's variants into the folder holding
+    /// them all, as `<stem>.json`.
+    pub write_variant_json: bool,
+    /// Which metadata is copied from each input into its output.
+    pub metadata: MetadataPolicy,
+    /// Directory the compressed files are written to.
     pub output_directory: String,
+    /// Output file name, built from the tokens in
+    /// [`FILENAME_TOKENS`](crate::compression::FILENAME_TOKENS). May contain `/` to
+    /// write into subfolders.
+    pub filename_template: String,
+    /// What to do when an output file already exists.
+    pub conflict_policy: ConflictPolicy,
+    /// Search each file for the lowest quality that still reaches
+    /// [`visual_target`](Self::visual_target), instead of using the fixed quality.
+    pub match_visual_quality: bool,
+    /// Visual quality searched for when
+    /// [`match_visual_quality`](Self::match_visual_quality) is on.
+    pub visual_target: VisualTarget,
+    /// Keep every output within [`max_file_size`](Self::max_file_size) by
+    /// searching for the highest quality that fits.
+    pub limit_file_size: bool,
+    /// Largest allowed output, in bytes.
+    pub max_file_size: u64,
+    /// Downscale images that do not fit even at the lowest quality, or that
+    /// are in a format without a quality setting.
+    pub downscale_to_fit: bool,
+    /// Compare each output with its input and report SSIM, PSNR and DSSIM.
+    /// Decoding both images again makes compression slower.
+    pub measure_quality: bool,
+    /// Skip files whose output is not smaller than the input by at least
+    /// [`min_savings_percent`](Self::min_savings_percent), discarding the output.
+    pub require_savings: bool,
+    /// Smallest saving, in percent of the input size, worth keeping an output for.
+    pub min_savings_percent: f32,
+    /// Copy skipped inputs to the output directory unchanged, so it holds every
+    /// file of the batch. The copy keeps the input's extension.
+    pub copy_original_on_skip: bool,
+    /// Only let [`OutputFormat::Auto`] pick lossless encodings, so screenshots
+    /// and text stay pixel exact.
+    pub lossless_only: bool,
+    /// Replace each input with its compressed version instead of writing into
+    /// [`output_directory`](Self::output_directory). The file keeps its name,
+    /// with the extension changed if the format changes. Another file already
+    /// holding that name is never overwritten; the output is renamed instead
+    /// under [`ConflictPolicy::Overwrite`].
+    pub in_place: bool,
+    /// How originals replaced in place are kept.
+    pub backup_mode: BackupMode,
+    /// Folder holding the backups and manifests of in-place batches, used to
+    /// restore them later.
+    pub backup_directory: String,
+    /// Recreate each file's folders relative to [`input_root`](Self::input_root)
+    /// inside the output directory instead of writing every file next to each other.
+    pub mirror_structure: bool,
+    /// Folder whose structure is mirrored. Files outside it are written
+    /// directly into the output directory.
+    pub input_root: String,
+    /// Number of files compressed concurrently, 0 uses every available core.
+    pub worker_threads: usize,
+    /// How often a file is retried after a transient I/O error, such as a
+    /// timeout on a network drive. Corrupt inputs are never retried.
+    pub io_retries: u32,
 }
 
 impl Default for CompressionSettings {
@@ -20,25 +575,186 @@ impl Default for CompressionSettings {
         Self {
             output_format: OutputFormat::Png,
             png_level: 6,
+            png: PngSettings::default(),
             webp_quality: 80.0,
+            webp: WebPSettings::default(),
             jpeg_quality: 85,
-            preserve_metadata: false,
+            avif_quality: 70.0,
+            avif: AvifSettings::default(),
+            jxl_quality: 90.0,
+            jxl: JpegXlSettings::default(),
+            resize_images: false,
+            resize: ResizeSettings::default(),
+            generate_variants: false,
+            variants: [480, 960, 1920]
+                .into_iter()
+                .flat_map(|width| {
+                    [OutputFormat::WebP, OutputFormat::Jpeg]
+                        .map(|format| OutputVariant { width, format })
+                })
+                .collect(),
+            write_picture_html: false,
+            write_variant_json: false,
+            metadata: MetadataPolicy::default(),
             output_directory: "output".to_string(),
+            filename_template: DEFAULT_FILENAME_TEMPLATE.to_string(),
+            conflict_policy: ConflictPolicy::default(),
+            match_visual_quality: false,
+            visual_target: VisualTarget::default(),
+            limit_file_size: false,
+            max_file_size: 200 * 1024,
+            downscale_to_fit: true,
+            measure_quality: false,
+            require_savings: false,
+            min_savings_percent: 0.0,
+            copy_original_on_skip: false,
+            lossless_only: false,
+            in_place: false,
+            backup_mode: BackupMode::default(),
+            backup_directory: "backup".to_string(),
+            mirror_structure: false,
+            input_root: String::new(),
+            worker_threads: 0,
+            io_retries: 2,
         }
     }
 }
 
 impl CompressionSettings {
-    pub fn validate(&self) -> Result<(), String> {
+    /// Checks that every value is within its supported range.
+    pub fn validate(&self) -> Result<(), PixelGuardError> {
+        let invalid = |message: &str| PixelGuardError::InvalidSettings(message.to_string());
+
         if self.png_level < 1 || self.png_level > 6 {
-            return Err("PNG level must be 1-6".to_string());
+            return Err(invalid("PNG level must be 1-6"));
+        }
+        if self.png.deflater == (PngDeflater::Zopfli { iterations: 0 }) {
+            return Err(invalid("Zopfli iterations must be 1-255"));
         }
         if self.webp_quality < 0.0 || self.webp_quality > 100.0 {
-            return Err("WebP quality must be 0-100".to_string());
+            return Err(invalid("WebP quality must be 0-100"));
+        }
+        if self.webp.method > 6 {
+            return Err(invalid("WebP method must be 0-6"));
+        }
+        if self.webp.near_lossless > 100 {
+            return Err(invalid("WebP near-lossless level must be 0-100"));
         }
         if self.jpeg_quality < 1 || self.jpeg_quality > 100 {
-            return Err("JPEG quality must be 1-100".to_string());
+            return Err(invalid("JPEG quality must be 1-100"));
+        }
+        if self.avif_quality < 1.0 || self.avif_quality > 100.0 {
+            return Err(invalid("AVIF quality must be 1-100"));
+        }
+        if self.avif.speed < 1 || self.avif.speed > 10 {
+            return Err(invalid("AVIF speed must be 1-10"));
+        }
+        if self.jxl_quality < 0.0 || self.jxl_quality > 100.0 {
+            return Err(invalid("JPEG XL quality must be 0-100"));
+        }
+        if self.jxl.effort < 1 || self.jxl.effort > 9 {
+            return Err(invalid("JPEG XL effort must be 1-9"));
+        }
+        if self.resize_images {
+            let resize = &self.resize;
+            match resize.mode {
+                ResizeMode::Fit if resize.max_width == 0 && resize.max_height == 0 => {
+                    return Err(invalid("Set a maximum width or height to resize to"));
+                }
+                ResizeMode::Fill | ResizeMode::Exact
+                    if resize.max_width == 0 || resize.max_height == 0 =>
+                {
+                    return Err(invalid(
+                        "Set both a width and a height to fill or stretch to",
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if self.generate_variants {
+            if self.variants.is_empty() {
+                return Err(invalid("Add at least one output variant"));
+            }
+            if self.in_place {
+                return Err(invalid("Variants cannot be written in place"));
+            }
+            for variant in &self.variants {
+                self.for_variant(variant).validate()?;
+            }
+        }
+        if self.match_visual_quality {
+            match self.visual_target {
+                VisualTarget::MinSsim(ssim) if !(0.0..=1.0).contains(&ssim) => {
+                    return Err(invalid("Minimum SSIM must be 0-1"));
+                }
+                VisualTarget::MaxDssim(dssim) if dssim < 0.0 => {
+                    return Err(invalid("Maximum DSSIM must not be negative"));
+                }
+                _ => {}
+            }
+            if self.limit_file_size {
+                return Err(invalid(
+                    "Choose either a visual quality target or a file size limit",
+                ));
+            }
+            // Outputs are decoded to measure them, which these formats do not support yet
+            if matches!(
+                self.output_format,
+                OutputFormat::Avif | OutputFormat::JpegXl
+            ) {
+                return Err(invalid(
+                    "Visual quality targets are not available for AVIF and JPEG XL",
+                ));
+            }
+        }
+        if self.limit_file_size && self.max_file_size == 0 {
+            return Err(invalid("Maximum file size must be greater than zero"));
+        }
+        if self.min_savings_percent < 0.0 || self.min_savings_percent >= 100.0 {
+            return Err(invalid("Minimum savings must be 0-99%"));
+        }
+        template::validate(&self.filename_template)?;
+        if self.in_place
+            && self.backup_mode != BackupMode::None
+            && self.backup_directory.trim().is_empty()
+        {
+            return Err(invalid("Choose a backup folder for in-place compression"));
+        }
+        if self.mirror_structure && self.input_root.trim().is_empty() {
+            return Err(invalid(
+                "Choose an input root to mirror the folder structure",
+            ));
         }
         Ok(())
     }
+
+    /// Settings that write `variant` as the only output.
+    pub(crate) fn for_variant(&self, variant: &OutputVariant) -> CompressionSettings {
+        let mut settings = self.clone();
+        settings.output_format = variant.format.clone();
+        settings.generate_variants = false;
+        settings.filename_template = template::with_width_suffix(&self.filename_template);
+        if variant.width > 0 {
+            settings.resize_images = true;
+            settings.resize = ResizeSettings {
+                max_width: variant.width,
+                max_height: 0,
+                mode: ResizeMode::Fit,
+                never_upscale: true,
+                filter: self.resize.filter,
+            };
+        }
+        settings
+    }
+
+    /// Number of worker threads a batch uses, resolving 0 to the available parallelism.
+    pub fn worker_count(&self) -> usize {
+        if self.worker_threads > 0 {
+            self.worker_threads
+        } else {
+            std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1)
+        }
+    }
 }
@@ -0,0 +1,231 @@
+use crate::error::PixelGuardError;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Output filename template that reproduces PixelGuard's original naming.
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{stem}_compressed.{ext}";
+
+/// Tokens allowed in [`CompressionSettings::filename_template`](crate::compression::CompressionSettings::filename_template).
+///
+/// - `{stem}`: input file name without its extension
+/// - `{ext}`: extension of the output format, e.g. `jpg`
+/// - `{format}`: name of the output format, e.g. `jpeg`
+/// - `{width}`, `{height}`: dimensions of the output image
+/// - `{quality}`: quality setting used, or `lossless`
+/// - `{hash8}`: first 8 hex digits of the SHA-256 of the output
+/// - `{date}`: current UTC date as `YYYY-MM-DD`
+///
+/// Write `{{` and `}}` for literal braces.
+pub const FILENAME_TOKENS: &[&str] = &[
+    "stem", "ext", "format", "width", "height", "quality", "hash8", "date",
+];
+
+enum Segment<'a> {
+    Text(&'a str),
+    Token(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Segment<'_>>, PixelGuardError> {
+    let invalid = |message: String| PixelGuardError::InvalidSettings(message);
+
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("{{") {
+            segments.push(Segment::Text("{"));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            segments.push(Segment::Text("}"));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| invalid("Filename template has an unclosed '{'".to_string()))?;
+            let token = &after[..end];
+            if !FILENAME_TOKENS.contains(&token) {
+                return Err(invalid(format!(
+                    "Unknown filename template token '{{{}}}'",
+                    token
+                )));
+            }
+            segments.push(Segment::Token(token));
+            rest = &after[end + 1..];
+        } else if rest.starts_with('}') {
+            return Err(invalid(
+                "Filename template has a '}' without a matching '{'".to_string(),
+            ));
+        } else {
+            let end = rest.find(['{', '}']).unwrap_or(rest.len());
+            segments.push(Segment::Text(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    Ok(segments)
+}
+
+/// Fills in `template`, looking up each token with `value`.
+pub(crate) fn render(
+    template: &str,
+    mut value: impl FnMut(&str) -> String,
+) -> Result<String, PixelGuardError> {
+    Ok(parse(template)?
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text.to_string(),
+            Segment::Token(token) => value(token),
+        })
+        .collect())
+}
+
+/// Checks that `template` only uses known tokens and always produces a
+/// relative path that stays inside the output directory.
+pub(crate) fn validate(template: &str) -> Result<(), PixelGuardError> {
+    let sample = render(template, |token| format!("{}1", token))?;
+    let path = Path::new(&sample);
+    // A trailing separator would name a folder rather than a file
+    let stays_inside = path.file_name().is_some()
+        && !sample.ends_with(['/', std::path::MAIN_SEPARATOR])
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !stays_inside {
+        return Err(PixelGuardError::InvalidSettings(
+            "Filename template must produce a file name inside the output directory".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns `template` with `-{width}w` added after the stem, so outputs that
+/// only differ in width get distinct names. Templates that already use
+/// `{width}` are returned unchanged.
+pub(crate) fn with_width_suffix(template: &str) -> String {
+    const SUFFIX: &str = "-{width}w";
+    if template.contains("{width}") {
+        template.to_string()
+    } else if template.contains("{stem}") {
+        template.replacen("{stem}", &format!("{{stem}}{}", SUFFIX), 1)
+    } else if let Some(base) = template.strip_suffix(".{ext}") {
+        format!("{}{}.{{ext}}", base, SUFFIX)
+    } else {
+        format!("{}{}", template, SUFFIX)
+    }
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+pub(crate) fn current_date() -> String {
+    format_date(unix_seconds())
+}
+
+/// The current UTC date and time as `YYYY-MM-DD_HH-MM-SS`, safe to use in file names.
+pub(crate) fn current_timestamp() -> String {
+    let seconds = unix_seconds();
+    let time_of_day = seconds % 86_400;
+    format!(
+        "{}_{:02}-{:02}-{:02}",
+        format_date(seconds),
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn format_date(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Converts days since 1970-01-01 to a Gregorian date, using Howard
+/// Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_sample(template: &str) -> Result<String, PixelGuardError> {
+        render(template, |token| token.to_uppercase())
+    }
+
+    #[test]
+    fn tokens_are_filled_in() {
+        assert_eq!(
+            render_sample("{stem}_{width}x{height}.{ext}").unwrap(),
+            "STEM_WIDTHxHEIGHT.EXT"
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render_sample("{{{stem}}}").unwrap(), "{STEM}");
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        for template in ["{stem", "stem}", "{name}.{ext}", "{}"] {
+            assert!(
+                matches!(
+                    render_sample(template),
+                    Err(PixelGuardError::InvalidSettings(_))
+                ),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn templates_must_stay_inside_the_output_directory() {
+        assert!(validate("{format}/{stem}.{ext}").is_ok());
+        for template in ["../{stem}.{ext}", "/{stem}.{ext}", "{stem}/", ""] {
+            assert!(validate(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn width_suffix_goes_after_the_stem() {
+        assert_eq!(
+            with_width_suffix("{stem}_compressed.{ext}"),
+            "{stem}-{width}w_compressed.{ext}"
+        );
+        assert_eq!(with_width_suffix("photo.{ext}"), "photo-{width}w.{ext}");
+        assert_eq!(
+            with_width_suffix("{stem}@{width}.{ext}"),
+            "{stem}@{width}.{ext}"
+        );
+    }
+
+    #[test]
+    fn days_convert_to_gregorian_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_744), (2026, 10, 18));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::compression::{
+    CompressionEngine, CompressionResult, CompressionSettings, CompressionStatus, ConflictPolicy,
+    OutputFormat,
+};
+use crate::error::PixelGuardError;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Formats the `<img>` fallback of a `<picture>` is taken from, most widely
+/// supported first.
+const FALLBACK_ORDER: [OutputFormat; 6] = [
+    OutputFormat::Jpeg,
+    OutputFormat::Png,
+    OutputFormat::Gif,
+    OutputFormat::WebP,
+    OutputFormat::Avif,
+    OutputFormat::JpegXl,
+];
+
+/// Order of the `<source>` elements of a `<picture>`, smallest files first
+/// since browsers take the first type they support.
+const SOURCE_ORDER: [OutputFormat; 6] = [
+    OutputFormat::JpegXl,
+    OutputFormat::Avif,
+    OutputFormat::WebP,
+    OutputFormat::Png,
+    OutputFormat::Jpeg,
+    OutputFormat::Gif,
+];
+
+/// JSON manifest of one input's variants.
+#[derive(Serialize)]
+struct VariantListing<'a> {
+    source: &'a Path,
+    variants: Vec<ListedVariant>,
+}
+
+#[derive(Serialize)]
+struct ListedVariant {
+    /// Path relative to the manifest, with `/` separators.
+    path: String,
+    format: &'static str,
+    mime_type: &'static str,
+    width: u32,
+    height: u32,
+    size: u64,
+}
+
+/// Writes the `<picture>` snippet and JSON manifest `settings` ask for,
+/// listing the variants of `input` that were written. They are put in the
+/// deepest folder holding every variant, named after the input, and follow
+/// the conflict policy like any output. Nothing is written if no variant
+/// succeeded.
+pub(crate) fn write_listings(
+    input: &Path,
+    settings: &CompressionSettings,
+    results: &[CompressionResult],
+) -> Result<(), PixelGuardError> {
+    let written: Vec<_> = results
+        .iter()
+        .filter(|result| matches!(result.status, CompressionStatus::Success))
+        .collect();
+    if written.is_empty() {
+        return Ok(());
+    }
+    let Some(directory) = common_directory(written.iter().map(|result| &*result.output_path))
+    else {
+        return Err(PixelGuardError::Unsupported(format!(
+            "The variants of {} share no folder to list them in",
+            input.display()
+        )));
+    };
+    let directory = &*directory;
+
+    let mut variants: Vec<ListedVariant> = written
+        .iter()
+        .filter_map(|result| {
+            let format = result.output_format.as_ref()?;
+            let (width, height) = result.dimensions?;
+            Some(ListedVariant {
+                path: relative_url(&result.output_path, directory),
+                format: format.name(),
+                mime_type: format.mime_type(),
+                width,
+                height,
+                size: result.compressed_size,
+            })
+        })
+        .collect();
+    variants.sort_by_key(|variant| variant.width);
+
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    if settings.write_picture_html {
+        let path = directory.join(format!("{}.html", stem));
+        write(
+            &path,
+            picture_html(&variants).as_bytes(),
+            settings.conflict_policy,
+        )?;
+    }
+    if settings.write_variant_json {
+        let path = directory.join(format!("{}.json", stem));
+        let listing = VariantListing {
+            source: input,
+            variants,
+        };
+        let data = serde_json::to_vec_pretty(&listing)
+            .map_err(|e| PixelGuardError::write(&path, io::Error::other(e)))?;
+        write(&path, &data, settings.conflict_policy)?;
+    }
+    Ok(())
+}
+
+/// Builds a `<picture>` element with a `<source>` per format and an `<img>`
+/// fallback in the most widely supported one. `variants` are sorted by width.
+fn picture_html(variants: &[ListedVariant]) -> String {
+    let of_format = |format: &OutputFormat| {
+        variants
+            .iter()
+            .filter(|variant| variant.format == format.name())
+            .collect::<Vec<_>>()
+    };
+    let srcset = |variants: &[&ListedVariant]| {
+        variants
+            .iter()
+            .map(|variant| format!("{} {}w", variant.path, variant.width))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let fallback = FALLBACK_ORDER
+        .iter()
+        .find(|format| !of_format(format).is_empty());
+
+    let mut html = String::from("<picture>\n");
+    for format in SOURCE_ORDER
+        .iter()
+        .filter(|&format| Some(format) != fallback)
+    {
+        let variants = of_format(format);
+        if !variants.is_empty() {
+            let _ = writeln!(
+                html,
+                "  <source type=\"{}\" srcset=\"{}\" sizes=\"100vw\">",
+                format.mime_type(),
+                srcset(&variants)
+            );
+        }
+    }
+    if let Some(format) = fallback {
+        let variants = of_format(format);
+        let largest = variants[variants.len() - 1];
+        let _ = writeln!(
+            html,
+            "  <img src=\"{}\" srcset=\"{}\" sizes=\"100vw\" width=\"{}\" height=\"{}\" alt=\"\" loading=\"lazy\" decoding=\"async\">",
+            largest.path,
+            srcset(&variants),
+            largest.width,
+            largest.height
+        );
+    }
+    html.push_str("</picture>\n");
+    html
+}
+
+/// Deepest folder containing every path in `paths`, or `None` if they share
+/// none, such as paths on different drives.
+fn common_directory<'a>(mut paths: impl Iterator<Item = &'a Path>) -> Option<PathBuf> {
+    let mut directory = paths.next()?.parent()?.to_path_buf();
+    for path in paths {
+        while !path.starts_with(&directory) {
+            if !directory.pop() {
+                return None;
+            }
+        }
+    }
+    Some(directory)
+}
+
+/// Path of `path` relative to `directory`, which contains it, percent-encoded
+/// for use in a URL and therefore safe inside HTML attributes.
+fn relative_url(path: &Path, directory: &Path) -> String {
+    let relative = path.strip_prefix(directory).unwrap_or(path);
+    let relative = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut url = String::new();
+    for byte in relative.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            let _ = write!(url, "%{:02X}", byte);
+        }
+    }
+    url
+}
+
+/// Writes a listing under `policy`. A listing the policy skips is simply not
+/// written.
+fn write(path: &Path, data: &[u8], policy: ConflictPolicy) -> Result<(), PixelGuardError> {
+    match CompressionEngine::write_output(path, data, policy) {
+        Err(PixelGuardError::OutputExists { .. }) if policy == ConflictPolicy::Skip => Ok(()),
+        written => written.map(drop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn listed(format: OutputFormat, path: &str, width: u32) -> ListedVariant {
+        ListedVariant {
+            path: path.to_string(),
+            format: format.name(),
+            mime_type: format.mime_type(),
+            width,
+            height: width / 2,
+            size: 1000,
+        }
+    }
+
+    fn written(path: &Path, format: OutputFormat, width: u32) -> CompressionResult {
+        CompressionResult {
+            output_format: Some(format),
+            dimensions: Some((width, width / 2)),
+            ..CompressionResult::new(
+                PathBuf::from("photo.jpg"),
+                path.to_path_buf(),
+                4000,
+                1000,
+                Duration::ZERO,
+            )
+        }
+    }
+
+    #[test]
+    fn relative_urls_are_percent_encoded() {
+        assert_eq!(
+            relative_url(Path::new("/out/web p/ü.webp"), Path::new("/out")),
+            "web%20p/%C3%BC.webp"
+        );
+        assert_eq!(
+            relative_url(Path::new("/out/a&b.jpg"), Path::new("/out")),
+            "a%26b.jpg"
+        );
+    }
+
+    #[test]
+    fn listings_go_in_the_deepest_common_folder() {
+        let paths = [
+            Path::new("/out/webp/photo-480w.webp"),
+            Path::new("/out/jpeg/large/photo-960w.jpg"),
+        ];
+        assert_eq!(
+            common_directory(paths.into_iter()),
+            Some(PathBuf::from("/out"))
+        );
+        assert_eq!(
+            common_directory([Path::new("/out/webp/photo.webp")].into_iter()),
+            Some(PathBuf::from("/out/webp"))
+        );
+        assert_eq!(common_directory(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn picture_lists_smaller_formats_first_with_a_jpeg_fallback() {
+        let variants = [
+            listed(OutputFormat::WebP, "webp/p-480w.webp", 480),
+            listed(OutputFormat::Jpeg, "jpeg/p-480w.jpg", 480),
+            listed(OutputFormat::WebP, "webp/p-960w.webp", 960),
+            listed(OutputFormat::Avif, "p-960w.avif", 960),
+            listed(OutputFormat::Jpeg, "jpeg/p-960w.jpg", 960),
+        ];
+        assert_eq!(
+            picture_html(&variants),
+            "<picture>\n\
+             \x20 <source type=\"image/avif\" srcset=\"p-960w.avif 960w\" sizes=\"100vw\">\n\
+             \x20 <source type=\"image/webp\" srcset=\"webp/p-480w.webp 480w, webp/p-960w.webp 960w\" sizes=\"100vw\">\n\
+             \x20 <img src=\"jpeg/p-960w.jpg\" srcset=\"jpeg/p-480w.jpg 480w, jpeg/p-960w.jpg 960w\" sizes=\"100vw\" width=\"960\" height=\"480\" alt=\"\" loading=\"lazy\" decoding=\"async\">\n\
+             </picture>\n"
+        );
+    }
+
+    #[test]
+    fn listings_use_paths_relative_to_their_folder() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        let results = [
+            written(&root.join("webp/photo-960w.webp"), OutputFormat::WebP, 960),
+            written(&root.join("jpeg/photo-480w.jpg"), OutputFormat::Jpeg, 480),
+            CompressionResult::skipped(PathBuf::from("photo.jpg"), "too small".to_string()),
+        ];
+        let settings = CompressionSettings {
+            write_picture_html: true,
+            write_variant_json: true,
+            ..CompressionSettings::default()
+        };
+
+        write_listings(Path::new("photo.jpg"), &settings, &results).unwrap();
+
+        let html = std::fs::read_to_string(root.join("photo.html")).unwrap();
+        assert!(html.contains("srcset=\"webp/photo-960w.webp 960w\""));
+        assert!(html.contains("src=\"jpeg/photo-480w.jpg\""));
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.join("photo.json")).unwrap()).unwrap();
+        let paths: Vec<_> = json["variants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| variant["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["jpeg/photo-480w.jpg", "webp/photo-960w.webp"]);
+    }
+
+    #[test]
+    fn listings_follow_the_conflict_policy() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        let results = [written(
+            &root.join("photo-960w.webp"),
+            OutputFormat::WebP,
+            960,
+        )];
+        std::fs::write(root.join("photo.html"), b"mine").unwrap();
+
+        let settings = |conflict_policy| CompressionSettings {
+            write_picture_html: true,
+            conflict_policy,
+            ..CompressionSettings::default()
+        };
+        write_listings(
+            Path::new("photo.jpg"),
+            &settings(ConflictPolicy::Skip),
+            &results,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(root.join("photo.html")).unwrap(), b"mine");
+
+        assert!(matches!(
+            write_listings(
+                Path::new("photo.jpg"),
+                &settings(ConflictPolicy::Fail),
+                &results
+            ),
+            Err(PixelGuardError::OutputExists { .. })
+        ));
+
+        write_listings(
+            Path::new("photo.jpg"),
+            &settings(ConflictPolicy::Rename),
+            &results,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(root.join("photo.html")).unwrap(), b"mine");
+        assert!(root.join("photo-1.html").exists());
+    }
+}
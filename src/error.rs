@@ -0,0 +1,213 @@
+//! The error type returned by the compression engine.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Why a file could not be compressed.
+///
+/// Underlying errors are kept as [`source`](Error::source)s and shared with
+/// `Arc`, so errors can be cloned along with the
+/// [`CompressionResult`](crate::compression::CompressionResult) they belong to.
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum PixelGuardError {
+    /// The input file could not be read.
+    #[error("Failed to read {}: {source}", path.display())]
+    Read {
+        /// File that was being read.
+        path: PathBuf,
+        /// The I/O error.
+        #[source]
+        source: Arc<io::Error>,
+    },
+    /// An output file or directory could not be written.
+    #[error("Failed to write {}: {source}", path.display())]
+    Write {
+        /// File or directory that was being written.
+        path: PathBuf,
+        /// The I/O error.
+        #[source]
+        source: Arc<io::Error>,
+    },
+    /// The input is corrupt or not an image format PixelGuard can read.
+    #[error("Failed to decode image: {source}")]
+    Decode {
+        /// File that was being decoded.
+        path: PathBuf,
+        /// The decoder error.
+        #[source]
+        source: Arc<image::ImageError>,
+    },
+    /// The encoder for the output format failed.
+    #[error("{format} encoding failed: {message}")]
+    Encode {
+        /// Name of the output format, e.g. `WebP`.
+        format: &'static str,
+        /// Description of the failure.
+        message: String,
+        /// The encoder error, if the encoder reports a typed one.
+        #[source]
+        source: Option<Arc<dyn Error + Send + Sync>>,
+    },
+    /// The output file already exists and the conflict policy does not allow replacing it.
+    #[error("Output already exists: {}", path.display())]
+    OutputExists {
+        /// The existing file.
+        path: PathBuf,
+    },
+    /// The image cannot be stored in the output format, e.g. because it is too large.
+    #[error("{0}")]
+    Unsupported(String),
+    /// An external program needed for the output format is not installed.
+    #[error("{tool} is required for this format but was not found: {source}")]
+    MissingTool {
+        /// Name of the program.
+        tool: String,
+        /// The error from starting it.
+        #[source]
+        source: Arc<io::Error>,
+    },
+    /// The compression settings are out of range.
+    #[error("{0}")]
+    InvalidSettings(String),
+    /// The batch was cancelled before the file finished.
+    #[error("Cancelled")]
+    Cancelled,
+    /// Compressing the file panicked, e.g. on a decoder bug hit by a
+    /// malformed image. The rest of the batch carries on.
+    #[error("Compression crashed: {0}")]
+    Crashed(String),
+}
+
+/// Broad category of a [`PixelGuardError`], for grouping and retry decisions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// Reading or writing a file failed.
+    Io,
+    /// The operating system refused access to a file.
+    PermissionDenied,
+    /// The input is corrupt or unsupported.
+    Decode,
+    /// The encoder failed.
+    Encode,
+    /// The output file already exists.
+    Conflict,
+    /// The output format cannot hold the image.
+    Unsupported,
+    /// A required external program is missing.
+    MissingTool,
+    /// The settings are invalid.
+    InvalidSettings,
+    /// The batch was cancelled.
+    Cancelled,
+    /// Compression panicked.
+    Crashed,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Io => "I/O error",
+            Self::PermissionDenied => "Permission denied",
+            Self::Decode => "Unreadable input",
+            Self::Encode => "Encoder error",
+            Self::Conflict => "Output exists",
+            Self::Unsupported => "Unsupported by format",
+            Self::MissingTool => "Missing tool",
+            Self::InvalidSettings => "Invalid settings",
+            Self::Cancelled => "Cancelled",
+            Self::Crashed => "Crashed",
+        })
+    }
+}
+
+impl PixelGuardError {
+    /// Wraps an error from reading `path`.
+    pub fn read(path: &Path, source: io::Error) -> Self {
+        Self::Read {
+            path: path.to_path_buf(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// Wraps an error from writing `path`.
+    pub fn write(path: &Path, source: io::Error) -> Self {
+        Self::Write {
+            path: path.to_path_buf(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// Wraps an error from decoding `path`. I/O failures are reported as
+    /// [`Read`](Self::Read) errors so they are not mistaken for corrupt input.
+    pub fn decode(path: &Path, source: image::ImageError) -> Self {
+        match source {
+            image::ImageError::IoError(source) => Self::read(path, source),
+            source => Self::Decode {
+                path: path.to_path_buf(),
+                source: Arc::new(source),
+            },
+        }
+    }
+
+    /// Wraps a typed error from the `format` encoder.
+    pub fn encode(format: &'static str, source: impl Error + Send + Sync + 'static) -> Self {
+        Self::Encode {
+            format,
+            message: source.to_string(),
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    /// Creates an encoder error for encoders that only report a description.
+    pub fn encode_message(format: &'static str, message: impl Into<String>) -> Self {
+        Self::Encode {
+            format,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Returns the category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Read { source, .. } | Self::Write { source, .. } => {
+                if source.kind() == io::ErrorKind::PermissionDenied {
+                    ErrorKind::PermissionDenied
+                } else {
+                    ErrorKind::Io
+                }
+            }
+            Self::Decode { .. } => ErrorKind::Decode,
+            Self::Encode { .. } => ErrorKind::Encode,
+            Self::OutputExists { .. } => ErrorKind::Conflict,
+            Self::Unsupported(_) => ErrorKind::Unsupported,
+            Self::MissingTool { .. } => ErrorKind::MissingTool,
+            Self::InvalidSettings(_) => ErrorKind::InvalidSettings,
+            Self::Cancelled => ErrorKind::Cancelled,
+            Self::Crashed(_) => ErrorKind::Crashed,
+        }
+    }
+
+    /// Returns `true` if trying again may succeed, e.g. after a timeout or an
+    /// interrupted read on a network drive. Corrupt inputs and encoder
+    /// failures are never transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Read { source, .. } | Self::Write { source, .. } => matches!(
+                source.kind(),
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ResourceBusy
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+}
@@ -1,37 +1,52 @@
-use std::path::PathBuf;
+//! Input file discovery and description.
 
+mod scan;
+
+pub use scan::ScanOptions;
+
+use std::path::{Path, PathBuf};
+
+/// An input image queued for compression.
 #[derive(Clone, Debug)]
 pub struct ImageFile {
+    /// Location of the file.
     pub path: PathBuf,
+    /// File name shown to the user.
     pub name: String,
+    /// Size of the file in bytes.
     pub size: u64,
+    /// Format guessed from the file extension.
     pub format: ImageFormat,
 }
 
+/// Input image format, detected from the file extension.
 #[derive(Clone, Debug, PartialEq)]
 pub enum ImageFormat {
+    /// JPEG image.
     Jpeg,
+    /// PNG image.
     Png,
+    /// WebP image.
     WebP,
+    /// GIF image, possibly animated.
     Gif,
+    /// Windows bitmap.
     Bmp,
+    /// TIFF image.
     Tiff,
+    /// Unrecognised extension.
     Unknown,
 }
 
+/// Helpers for finding and describing input files.
 pub struct FileManager;
 
 impl FileManager {
-    pub fn open_file_dialog() -> Option<Vec<PathBuf>> {
-        rfd::FileDialog::new()
-            .add_filter(
-                "Images",
-                &["jpg", "jpeg", "png", "webp", "gif", "bmp", "tiff"],
-            )
-            .set_title("Select Images")
-            .pick_files()
-    }
+    /// File extensions PixelGuard accepts as input.
+    pub const SUPPORTED_EXTENSIONS: &[&str] =
+        &["jpg", "jpeg", "png", "webp", "gif", "bmp", "tiff", "tif"];
 
+    /// Reads the size and format of the file at `path`, or `None` if it cannot be read.
     pub fn analyze_file(path: PathBuf) -> Option<ImageFile> {
         let metadata = std::fs::metadata(&path).ok()?;
         let name = path.file_name()?.to_string_lossy().to_string();
@@ -45,8 +60,12 @@ impl FileManager {
         })
     }
 
-    fn detect_format(path: &PathBuf) -> ImageFormat {
-        match path.extension().and_then(|s| s.to_str()) {
+    fn detect_format(path: &Path) -> ImageFormat {
+        let extension = path
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
             Some("jpg") | Some("jpeg") => ImageFormat::Jpeg,
             Some("png") => ImageFormat::Png,
             Some("webp") => ImageFormat::WebP,
@@ -57,6 +76,7 @@ impl FileManager {
         }
     }
 
+    /// Formats a byte count for display, e.g. `1.5 MB`.
     pub fn format_file_size(bytes: u64) -> String {
         const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
         let mut size = bytes as f64;
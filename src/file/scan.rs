@@ -0,0 +1,209 @@
+use crate::error::PixelGuardError;
+use crate::file::{FileManager, ImageFile};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path::Path;
+use walkdir::{DirEntry, WalkDir};
+
+/// Options for [`FileManager::scan_directory`].
+///
+/// Patterns are matched against paths relative to the scanned folder, using
+/// `/` as the separator on every platform. `*` also matches across folders,
+/// so `*.png` finds PNG files at any depth; use `**/name` to match a folder
+/// anywhere in the tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanOptions {
+    /// Only files matching one of these patterns are returned. Empty matches
+    /// every supported image.
+    pub include: Vec<String>,
+    /// Files and folders matching any of these patterns are left out.
+    pub exclude: Vec<String>,
+    /// How many folder levels below the scanned folder to enter, `None` for no
+    /// limit. `Some(0)` only looks at the folder itself.
+    pub max_depth: Option<usize>,
+    /// Include files and folders whose name starts with a dot.
+    pub include_hidden: bool,
+    /// Follow symbolic links to files and folders.
+    pub follow_symlinks: bool,
+}
+
+impl FileManager {
+    /// Recursively finds the supported images under `root`.
+    ///
+    /// Every match is described with [`analyze_file`](Self::analyze_file).
+    /// Entries that cannot be read are skipped. Results are sorted by path.
+    pub fn scan_directory(
+        root: &Path,
+        options: &ScanOptions,
+    ) -> Result<Vec<ImageFile>, PixelGuardError> {
+        let include = build_glob_set(&options.include)?;
+        let exclude = build_glob_set(&options.exclude)?;
+
+        let mut walker = WalkDir::new(root)
+            .follow_links(options.follow_symlinks)
+            .sort_by_file_name();
+        if let Some(depth) = options.max_depth {
+            walker = walker.max_depth(depth + 1);
+        }
+
+        let entries = walker.into_iter().filter_entry(|entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            if !options.include_hidden && is_hidden(entry) {
+                return false;
+            }
+            !exclude.is_match(relative_path(root, entry))
+        });
+
+        let files = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter(|entry| Self::is_supported(entry.path()))
+            .filter(|entry| {
+                options.include.is_empty() || include.is_match(relative_path(root, entry))
+            })
+            .filter_map(|entry| Self::analyze_file(entry.into_path()))
+            .collect();
+
+        Ok(files)
+    }
+
+    /// Returns `true` if `path` has one of the [`SUPPORTED_EXTENSIONS`](Self::SUPPORTED_EXTENSIONS).
+    pub fn is_supported(path: &Path) -> bool {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                Self::SUPPORTED_EXTENSIONS
+                    .iter()
+                    .any(|supported| supported.eq_ignore_ascii_case(extension))
+            })
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, PixelGuardError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| {
+            PixelGuardError::InvalidSettings(format!("Invalid pattern '{}': {}", pattern, e))
+        })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| PixelGuardError::InvalidSettings(format!("Invalid patterns: {}", e)))
+}
+
+fn relative_path(root: &Path, entry: &DirEntry) -> String {
+    let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Creates a folder holding empty files at each of the `/` separated paths.
+    fn tree(paths: &[&str]) -> TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        for path in paths {
+            let path = directory.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        directory
+    }
+
+    /// Scans `root` and returns the found files relative to it.
+    fn scan(root: &TempDir, options: &ScanOptions) -> Vec<String> {
+        FileManager::scan_directory(root.path(), options)
+            .unwrap()
+            .iter()
+            .map(|file| {
+                let relative = file.path.strip_prefix(root.path()).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extensions_match_regardless_of_case() {
+        for name in [
+            "a.jpg", "a.JPEG", "a.png", "a.tif", "a.TIF", "a.Tiff", "a.webp",
+        ] {
+            assert!(FileManager::is_supported(Path::new(name)), "{}", name);
+        }
+        for name in ["a.txt", "a", "a.jpg.bak", "tif"] {
+            assert!(!FileManager::is_supported(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn finds_supported_images_at_every_depth() {
+        let root = tree(&["a.png", "notes.txt", "scans/b.TIF", "scans/old/c.tif"]);
+        assert_eq!(
+            scan(&root, &ScanOptions::default()),
+            ["a.png", "scans/b.TIF", "scans/old/c.tif"]
+        );
+    }
+
+    #[test]
+    fn include_and_exclude_patterns_filter_paths() {
+        let root = tree(&["a.png", "b.jpg", "raw/c.png", "raw/d.jpg"]);
+        let options = ScanOptions {
+            include: vec!["*.png".to_string()],
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&root, &options), ["a.png", "raw/c.png"]);
+
+        let options = ScanOptions {
+            exclude: vec!["raw".to_string(), "b.*".to_string()],
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&root, &options), ["a.png"]);
+    }
+
+    #[test]
+    fn max_depth_limits_the_folders_entered() {
+        let root = tree(&["a.png", "one/b.png", "one/two/c.png"]);
+        let options = |max_depth| ScanOptions {
+            max_depth: Some(max_depth),
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&root, &options(0)), ["a.png"]);
+        assert_eq!(scan(&root, &options(1)), ["a.png", "one/b.png"]);
+    }
+
+    #[test]
+    fn hidden_files_and_folders_are_skipped_unless_included() {
+        let root = tree(&["a.png", ".b.png", ".cache/c.png"]);
+        assert_eq!(scan(&root, &ScanOptions::default()), ["a.png"]);
+
+        let options = ScanOptions {
+            include_hidden: true,
+            ..ScanOptions::default()
+        };
+        assert_eq!(scan(&root, &options), [".b.png", ".cache/c.png", "a.png"]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let root = tree(&[]);
+        let options = ScanOptions {
+            include: vec!["[".to_string()],
+            ..ScanOptions::default()
+        };
+        assert!(matches!(
+            FileManager::scan_directory(root.path(), &options),
+            Err(PixelGuardError::InvalidSettings(_))
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+//! PixelGuard image compression library.
+//!
+//! The [`compression`] module holds the engine that re-encodes images as PNG,
+//! WebP, JPEG, AVIF or JPEG XL, and the [`file`](mod@file) module discovers and describes input files.
+//! The desktop app and the `pixelguard compress` command are both built on
+//! this API.
+//!
+//! A typical embedding analyzes its inputs with [`file::FileManager::analyze_file`],
+//! configures a [`compression::CompressionSettings`] and hands both to
+//! [`compression::CompressionEngine::compress_files`], reading
+//! [`compression::CompressionProgress`] events from the channel while it runs.
+//! Files that fail carry an [`error::PixelGuardError`] describing why.
+
+#![warn(missing_docs)]
+
+pub mod compression;
+pub mod error;
+pub mod file;
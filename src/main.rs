@@ -1,12 +1,24 @@
 mod app;
-mod compression;
-mod file;
+mod cli;
 mod ui;
 
 use app::PixelGuardApp;
+use clap::Parser;
+use cli::{Cli, Command};
 use eframe::egui;
+use std::process::ExitCode;
 
-fn main() -> Result<(), eframe::Error> {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Compress(args)) => cli::run(*args),
+        Some(Command::Restore(args)) => cli::restore(args),
+        None => run_gui(),
+    }
+}
+
+fn run_gui() -> ExitCode {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([800.0, 600.0])
@@ -14,9 +26,17 @@ fn main() -> Result<(), eframe::Error> {
         ..Default::default()
     };
 
-    eframe::run_native(
+    let result = eframe::run_native(
         "PixelGuard - Lossless Image Compressor",
         options,
         Box::new(|_cc| Ok(Box::new(PixelGuardApp::new()))),
-    )
+    );
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,17 +1,25 @@
-use crate::compression::{
-    CompressionEngine, CompressionProgress, CompressionResult, CompressionSettings, OutputFormat,
-};
-use crate::file::ImageFile;
 use eframe::egui;
+use pixelguard::compression::{
+    AvifSubsampling, BackupMode, CancellationToken, CompressionEngine, CompressionProgress,
+    CompressionResult, CompressionSettings, ConflictPolicy, DEFAULT_FILENAME_TEMPLATE,
+    FILENAME_TOKENS, MetadataPolicy, OutputFormat, OutputVariant, PngDeflater, PngFilter,
+    ResizeFilter, ResizeMode, VisualTarget, WebPMode,
+};
+use pixelguard::file::ImageFile;
+use std::path::PathBuf;
 use std::sync::mpsc;
 
+const DEFAULT_ZOPFLI_ITERATIONS: u8 = 15;
+
 pub struct CompressionPanel {
     settings: CompressionSettings,
     progress_receiver: Option<mpsc::Receiver<CompressionProgress>>,
     result_sender: Option<mpsc::Sender<Vec<CompressionResult>>>,
+    cancel_token: CancellationToken,
     current_progress: f32,
     is_processing: bool,
     status_message: String,
+    preview_dimensions: Option<(PathBuf, (u32, u32))>,
 }
 
 impl CompressionPanel {
@@ -20,9 +28,11 @@ impl CompressionPanel {
             settings: CompressionSettings::default(),
             progress_receiver: None,
             result_sender: None,
+            cancel_token: CancellationToken::new(),
             current_progress: 0.0,
             is_processing: false,
             status_message: String::new(),
+            preview_dimensions: None,
         }
     }
 
@@ -34,7 +44,9 @@ impl CompressionPanel {
 
             self.render_format_selector(ui);
             self.render_quality_settings(ui);
-            self.render_output_settings(ui);
+            self.render_resize_settings(ui);
+            self.render_variant_settings(ui);
+            self.render_output_settings(ui, files.first());
 
             ui.separator();
 
@@ -46,6 +58,10 @@ impl CompressionPanel {
         });
     }
 
+    pub fn set_input_root(&mut self, folder: &std::path::Path) {
+        self.settings.input_root = folder.to_string_lossy().to_string();
+    }
+
     pub fn set_result_sender(&mut self, sender: mpsc::Sender<Vec<CompressionResult>>) {
         self.result_sender = Some(sender);
     }
@@ -53,28 +69,69 @@ impl CompressionPanel {
     fn render_format_selector(&mut self, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
             ui.label("Format:");
-            egui::ComboBox::from_id_source("format")
-                .selected_text(format!("{:?}", self.settings.output_format))
+            egui::ComboBox::from_id_salt("format")
+                .selected_text(self.format_label())
                 .show_ui(ui, |ui| {
                     ui.selectable_value(
                         &mut self.settings.output_format,
                         OutputFormat::Png,
                         "PNG (Lossless)",
                     );
+
+                    let is_webp = self.settings.output_format == OutputFormat::WebP;
+                    let is_lossless = self.settings.webp.mode == WebPMode::Lossless;
+                    if ui
+                        .selectable_label(is_webp && !is_lossless, "WebP")
+                        .clicked()
+                    {
+                        self.settings.output_format = OutputFormat::WebP;
+                        if is_lossless {
+                            self.settings.webp.mode = WebPMode::Lossy;
+                        }
+                    }
+                    if ui
+                        .selectable_label(is_webp && is_lossless, "WebP (Lossless)")
+                        .clicked()
+                    {
+                        self.settings.output_format = OutputFormat::WebP;
+                        self.settings.webp.mode = WebPMode::Lossless;
+                    }
+
+                    ui.selectable_value(
+                        &mut self.settings.output_format,
+                        OutputFormat::Jpeg,
+                        "JPEG",
+                    );
                     ui.selectable_value(
                         &mut self.settings.output_format,
-                        OutputFormat::WebP,
-                        "WebP",
+                        OutputFormat::Avif,
+                        "AVIF",
                     );
                     ui.selectable_value(
                         &mut self.settings.output_format,
-                        OutputFormat::Jpeg,
-                        "JPEG",
+                        OutputFormat::JpegXl,
+                        "JPEG XL",
+                    );
+                    ui.selectable_value(&mut self.settings.output_format, OutputFormat::Gif, "GIF");
+                    ui.selectable_value(
+                        &mut self.settings.output_format,
+                        OutputFormat::Auto,
+                        "Auto (smallest per image)",
                     );
                 });
         });
     }
 
+    fn format_label(&self) -> String {
+        match (&self.settings.output_format, self.settings.webp.mode) {
+            (OutputFormat::WebP, WebPMode::Lossless) => "WebP (Lossless)".to_string(),
+            (OutputFormat::WebP, WebPMode::NearLossless) => "WebP (Near-lossless)".to_string(),
+            (OutputFormat::JpegXl, _) => "JPEG XL".to_string(),
+            (OutputFormat::Auto, _) => "Auto (smallest per image)".to_string(),
+            (format, _) => format!("{:?}", format),
+        }
+    }
+
     fn render_quality_settings(&mut self, ui: &mut egui::Ui) {
         match self.settings.output_format {
             OutputFormat::Png => {
@@ -82,37 +139,491 @@ impl CompressionPanel {
                     ui.label("Level:");
                     ui.add(egui::Slider::new(&mut self.settings.png_level, 1..=6));
                 });
+                self.render_png_options(ui);
             }
             OutputFormat::WebP => {
+                let webp = &mut self.settings.webp;
+                ui.horizontal(|ui| {
+                    ui.label("Mode:");
+                    ui.radio_value(&mut webp.mode, WebPMode::Lossy, "Lossy");
+                    ui.radio_value(&mut webp.mode, WebPMode::NearLossless, "Near-lossless");
+                    ui.radio_value(&mut webp.mode, WebPMode::Lossless, "Lossless");
+                });
+                match webp.mode {
+                    WebPMode::Lossy => {
+                        ui.horizontal(|ui| {
+                            ui.label("Quality:");
+                            ui.add(egui::Slider::new(
+                                &mut self.settings.webp_quality,
+                                0.0..=100.0,
+                            ));
+                        });
+                    }
+                    WebPMode::NearLossless => {
+                        ui.horizontal(|ui| {
+                            ui.label("Near-lossless:");
+                            ui.add(egui::Slider::new(&mut webp.near_lossless, 0..=100));
+                        });
+                    }
+                    WebPMode::Lossless => {}
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Effort:");
+                    ui.add(egui::Slider::new(&mut webp.method, 0..=6));
+                });
+            }
+            OutputFormat::Jpeg => {
+                ui.horizontal(|ui| {
+                    ui.label("Quality:");
+                    ui.add(egui::Slider::new(&mut self.settings.jpeg_quality, 1..=100));
+                });
+            }
+            OutputFormat::Avif => {
                 ui.horizontal(|ui| {
                     ui.label("Quality:");
                     ui.add(egui::Slider::new(
-                        &mut self.settings.webp_quality,
-                        0.0..=100.0,
+                        &mut self.settings.avif_quality,
+                        1.0..=100.0,
                     ));
                 });
+                let avif = &mut self.settings.avif;
+                ui.horizontal(|ui| {
+                    ui.label("Speed:");
+                    ui.add(egui::Slider::new(&mut avif.speed, 1..=10));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Chroma:");
+                    ui.radio_value(&mut avif.subsampling, AvifSubsampling::Yuv444, "4:4:4");
+                    ui.radio_value(&mut avif.subsampling, AvifSubsampling::Yuv422, "4:2:2");
+                    ui.radio_value(&mut avif.subsampling, AvifSubsampling::Yuv420, "4:2:0");
+                });
             }
-            OutputFormat::Jpeg => {
+            OutputFormat::JpegXl => {
                 ui.horizontal(|ui| {
                     ui.label("Quality:");
-                    ui.add(egui::Slider::new(&mut self.settings.jpeg_quality, 1..=100));
+                    ui.add(egui::Slider::new(
+                        &mut self.settings.jxl_quality,
+                        0.0..=100.0,
+                    ));
+                });
+                let jxl = &mut self.settings.jxl;
+                ui.horizontal(|ui| {
+                    ui.label("Effort:");
+                    ui.add(egui::Slider::new(&mut jxl.effort, 1..=9));
                 });
+                ui.checkbox(&mut jxl.lossless_jpeg, "Losslessly recompress JPEG inputs")
+                    .on_hover_text("The original JPEG can be restored exactly with djxl, apart from stripped metadata");
+            }
+            OutputFormat::Gif => {
+                ui.weak("Each frame is reduced to a 256 color palette");
+            }
+            OutputFormat::Auto => {
+                ui.weak(
+                    "Tries PNG, WebP, JPEG, AVIF and JPEG XL with their own settings and keeps the smallest",
+                );
+                ui.checkbox(&mut self.settings.lossless_only, "Lossless only")
+                    .on_hover_text("Keep screenshots and text pixel exact");
             }
         }
+        if !self.settings.output_format.supports_animation()
+            && self.settings.output_format != OutputFormat::Auto
+        {
+            ui.weak("Animated inputs keep only their first frame in this format");
+        }
     }
 
-    fn render_output_settings(&mut self, ui: &mut egui::Ui) {
+    fn render_resize_settings(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.settings.resize_images, "Resize");
+        if !self.settings.resize_images {
+            return;
+        }
+
+        let resize = &mut self.settings.resize;
+        ui.horizontal(|ui| {
+            ui.label("Size:");
+            ui.add(egui::DragValue::new(&mut resize.max_width).suffix(" px"));
+            ui.label("×");
+            ui.add(egui::DragValue::new(&mut resize.max_height).suffix(" px"));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Mode:");
+            ui.radio_value(&mut resize.mode, ResizeMode::Fit, "Fit")
+                .on_hover_text(
+                    "Fit inside the size, keeping the aspect ratio. 0 leaves a side free",
+                );
+            ui.radio_value(&mut resize.mode, ResizeMode::Fill, "Fill")
+                .on_hover_text("Cover the size, keeping the aspect ratio, and crop the rest");
+            ui.radio_value(&mut resize.mode, ResizeMode::Exact, "Exact")
+                .on_hover_text("Stretch to the size");
+        });
         ui.horizontal(|ui| {
-            ui.label("Output:");
-            ui.text_edit_singleline(&mut self.settings.output_directory);
-            if ui.button("Browse").clicked() {
-                if let Some(path) = rfd::FileDialog::new().pick_folder() {
+            ui.label("Filter:");
+            egui::ComboBox::from_id_salt("resize_filter")
+                .selected_text(format!("{:?}", resize.filter))
+                .show_ui(ui, |ui| {
+                    for filter in [
+                        ResizeFilter::Nearest,
+                        ResizeFilter::Triangle,
+                        ResizeFilter::CatmullRom,
+                        ResizeFilter::Gaussian,
+                        ResizeFilter::Lanczos3,
+                    ] {
+                        ui.selectable_value(&mut resize.filter, filter, format!("{:?}", filter));
+                    }
+                });
+        });
+        ui.checkbox(&mut resize.never_upscale, "Never upscale")
+            .on_hover_text("Leave smaller images at their size");
+    }
+
+    fn render_variant_settings(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(
+            &mut self.settings.generate_variants,
+            "Write responsive variants",
+        )
+        .on_hover_text("Write several widths and formats of every image, e.g. for srcset");
+        if !self.settings.generate_variants {
+            return;
+        }
+
+        let mut removed = None;
+        for (index, variant) in self.settings.variants.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut variant.width).suffix(" px"))
+                    .on_hover_text("0 keeps the original width");
+                egui::ComboBox::from_id_salt(("variant_format", index))
+                    .selected_text(variant.format.name().to_uppercase())
+                    .show_ui(ui, |ui| {
+                        for format in [
+                            OutputFormat::Png,
+                            OutputFormat::WebP,
+                            OutputFormat::Jpeg,
+                            OutputFormat::Avif,
+                            OutputFormat::JpegXl,
+                            OutputFormat::Gif,
+                            OutputFormat::Auto,
+                        ] {
+                            let label = format.name().to_uppercase();
+                            ui.selectable_value(&mut variant.format, format, label);
+                        }
+                    });
+                if ui.small_button("✕").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+        if let Some(index) = removed {
+            self.settings.variants.remove(index);
+        }
+        if ui.button("Add variant").clicked() {
+            let variant = self
+                .settings
+                .variants
+                .last()
+                .cloned()
+                .unwrap_or(OutputVariant {
+                    width: 0,
+                    format: OutputFormat::WebP,
+                });
+            self.settings.variants.push(variant);
+        }
+        ui.weak("Each variant uses its format's quality settings above");
+        ui.checkbox(
+            &mut self.settings.write_picture_html,
+            "Write <picture> HTML",
+        );
+        ui.checkbox(&mut self.settings.write_variant_json, "Write JSON manifest");
+    }
+
+    fn render_png_options(&mut self, ui: &mut egui::Ui) {
+        let png = &mut self.settings.png;
+
+        egui::CollapsingHeader::new("Advanced PNG options").show(ui, |ui| {
+            ui.horizontal_wrapped(|ui| {
+                ui.label("Filters:");
+                for filter in PngFilter::ALL {
+                    let mut enabled = png.filters.contains(&filter);
+                    if ui.checkbox(&mut enabled, format!("{:?}", filter)).changed() {
+                        if enabled {
+                            png.filters.push(filter);
+                        } else {
+                            png.filters.retain(|&f| f != filter);
+                        }
+                    }
+                }
+            });
+            if png.filters.is_empty() {
+                ui.weak("No filters selected, the level's defaults are used");
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Deflate:");
+                let is_zopfli = matches!(png.deflater, PngDeflater::Zopfli { .. });
+                if ui.radio(!is_zopfli, "libdeflate").clicked() {
+                    png.deflater = PngDeflater::Libdeflater;
+                }
+                if ui.radio(is_zopfli, "Zopfli (slow)").clicked() && !is_zopfli {
+                    png.deflater = PngDeflater::Zopfli {
+                        iterations: DEFAULT_ZOPFLI_ITERATIONS,
+                    };
+                }
+                if let PngDeflater::Zopfli { iterations } = &mut png.deflater {
+                    ui.add(egui::Slider::new(iterations, 1..=255).text("iterations"));
+                }
+            });
+
+            ui.checkbox(&mut png.interlace, "Interlace (Adam7)");
+            ui.checkbox(&mut png.bit_depth_reduction, "Reduce bit depth");
+            ui.checkbox(&mut png.color_type_reduction, "Reduce color type");
+            ui.checkbox(&mut png.palette_reduction, "Reduce palette");
+            ui.checkbox(&mut png.grayscale_reduction, "Reduce to grayscale");
+        });
+    }
+
+    fn render_output_settings(&mut self, ui: &mut egui::Ui, preview_file: Option<&ImageFile>) {
+        ui.checkbox(&mut self.settings.in_place, "Compress in place")
+            .on_hover_text("Replace each input with its compressed version");
+        if self.settings.in_place {
+            self.render_backup_settings(ui);
+        }
+
+        ui.add_enabled_ui(!self.settings.in_place, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Output:");
+                ui.text_edit_singleline(&mut self.settings.output_directory);
+                if ui.button("Browse").clicked()
+                    && let Some(path) = rfd::FileDialog::new().pick_folder()
+                {
                     self.settings.output_directory = path.to_string_lossy().to_string();
                 }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Filename:");
+                let tokens = FILENAME_TOKENS
+                    .iter()
+                    .map(|token| format!("{{{}}}", token))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                ui.text_edit_singleline(&mut self.settings.filename_template)
+                    .on_hover_text(format!("Available tokens: {}", tokens));
+                if ui.button("Reset").clicked() {
+                    self.settings.filename_template = DEFAULT_FILENAME_TEMPLATE.to_string();
+                }
+            });
+        });
+        if let Some(file) = preview_file {
+            self.render_filename_preview(ui, file);
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("If the output exists:");
+            let policy = &mut self.settings.conflict_policy;
+            ui.radio_value(policy, ConflictPolicy::Overwrite, "Overwrite");
+            ui.radio_value(policy, ConflictPolicy::Skip, "Skip");
+            ui.radio_value(policy, ConflictPolicy::Rename, "Rename");
+            ui.radio_value(policy, ConflictPolicy::Fail, "Fail");
+        });
+
+        if !self.settings.in_place {
+            ui.checkbox(
+                &mut self.settings.mirror_structure,
+                "Mirror folder structure",
+            );
+            if self.settings.mirror_structure {
+                ui.horizontal(|ui| {
+                    ui.label("Input root:");
+                    ui.text_edit_singleline(&mut self.settings.input_root);
+                    if ui.button("Browse").clicked()
+                        && let Some(path) = rfd::FileDialog::new().pick_folder()
+                    {
+                        self.settings.input_root = path.to_string_lossy().to_string();
+                    }
+                });
+            }
+        }
+
+        ui.horizontal(|ui| {
+            if ui
+                .checkbox(
+                    &mut self.settings.match_visual_quality,
+                    "Match visual quality:",
+                )
+                .on_hover_text("Pick the lowest quality that still looks this good")
+                .changed()
+                && self.settings.match_visual_quality
+            {
+                self.settings.limit_file_size = false;
             }
+            ui.add_enabled_ui(self.settings.match_visual_quality, |ui| {
+                let target = &mut self.settings.visual_target;
+                if ui
+                    .selectable_label(matches!(target, VisualTarget::MinSsim(_)), "SSIM ≥")
+                    .clicked()
+                {
+                    *target = VisualTarget::MinSsim(0.98);
+                }
+                if ui
+                    .selectable_label(matches!(target, VisualTarget::MaxDssim(_)), "DSSIM ≤")
+                    .clicked()
+                {
+                    *target = VisualTarget::MaxDssim(0.002);
+                }
+                match target {
+                    VisualTarget::MinSsim(ssim) => ui.add(
+                        egui::DragValue::new(ssim)
+                            .range(0.0..=1.0)
+                            .speed(0.001)
+                            .fixed_decimals(3),
+                    ),
+                    VisualTarget::MaxDssim(dssim) => ui.add(
+                        egui::DragValue::new(dssim)
+                            .range(0.0..=1.0)
+                            .speed(0.0001)
+                            .fixed_decimals(4),
+                    ),
+                };
+            });
         });
 
-        ui.checkbox(&mut self.settings.preserve_metadata, "Preserve metadata");
+        ui.horizontal(|ui| {
+            if ui
+                .checkbox(&mut self.settings.limit_file_size, "Limit file size to")
+                .on_hover_text("Pick the highest quality that fits")
+                .changed()
+                && self.settings.limit_file_size
+            {
+                self.settings.match_visual_quality = false;
+            }
+            let mut kilobytes = self.settings.max_file_size / 1024;
+            if ui
+                .add_enabled(
+                    self.settings.limit_file_size,
+                    egui::DragValue::new(&mut kilobytes)
+                        .range(1..=u64::MAX / 1024)
+                        .suffix(" KB"),
+                )
+                .changed()
+            {
+                self.settings.max_file_size = kilobytes * 1024;
+            }
+            if self.settings.limit_file_size {
+                ui.checkbox(&mut self.settings.downscale_to_fit, "Downscale if needed");
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.checkbox(
+                &mut self.settings.require_savings,
+                "Skip files that don't shrink by at least",
+            );
+            ui.add_enabled(
+                self.settings.require_savings,
+                egui::DragValue::new(&mut self.settings.min_savings_percent)
+                    .range(0.0..=99.0)
+                    .suffix("%"),
+            );
+        });
+        if self.settings.require_savings && !self.settings.in_place {
+            ui.checkbox(
+                &mut self.settings.copy_original_on_skip,
+                "Copy skipped originals to the output",
+            );
+        }
+
+        ui.checkbox(
+            &mut self.settings.measure_quality,
+            "Measure quality (SSIM, PSNR, DSSIM)",
+        )
+        .on_hover_text("Compare each output with its input. Slower");
+
+        ui.horizontal(|ui| {
+            ui.label("Workers:");
+            let max_workers = std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1);
+            ui.add(
+                egui::Slider::new(&mut self.settings.worker_threads, 0..=max_workers)
+                    .custom_formatter(|value, _| {
+                        if value == 0.0 {
+                            "Auto".to_string()
+                        } else {
+                            format!("{}", value)
+                        }
+                    }),
+            );
+        });
+
+        self.render_metadata_settings(ui);
+    }
+
+    fn render_metadata_settings(&mut self, ui: &mut egui::Ui) {
+        let policy = &mut self.settings.metadata;
+        ui.horizontal(|ui| {
+            ui.label("Metadata:");
+            ui.radio_value(policy, MetadataPolicy::strip_all(), "Strip all");
+            ui.radio_value(policy, MetadataPolicy::privacy(), "Privacy")
+                .on_hover_text("Keep everything but location, serial numbers and thumbnails");
+            ui.radio_value(policy, MetadataPolicy::keep_all(), "Keep all")
+                .on_hover_text("AVIF keeps EXIF only, GIF nothing");
+        });
+        ui.checkbox(&mut policy.keep_icc, "Keep ICC color profile");
+        ui.checkbox(&mut policy.keep_copyright, "Keep author and copyright");
+        ui.checkbox(&mut policy.strip_all, "Strip all other metadata");
+        ui.add_enabled_ui(!policy.strip_all, |ui| {
+            ui.checkbox(&mut policy.strip_gps, "Strip GPS location");
+            ui.checkbox(&mut policy.strip_serial_numbers, "Strip serial numbers")
+                .on_hover_text("Camera and lens serial numbers, and maker notes");
+            ui.checkbox(&mut policy.strip_thumbnails, "Strip thumbnails");
+        });
+    }
+
+    fn render_backup_settings(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Keep originals:");
+            let mode = &mut self.settings.backup_mode;
+            ui.radio_value(mode, BackupMode::Folder, "Backup folder");
+            ui.radio_value(mode, BackupMode::Trash, "Trash");
+            ui.radio_value(mode, BackupMode::None, "Don't keep");
+        });
+
+        if self.settings.backup_mode == BackupMode::None {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "Originals are replaced and cannot be restored",
+            );
+        } else {
+            ui.horizontal(|ui| {
+                ui.label("Backups:");
+                ui.text_edit_singleline(&mut self.settings.backup_directory)
+                    .on_hover_text(
+                        "Each batch gets a folder here with a manifest for restoring it",
+                    );
+                if ui.button("Browse").clicked()
+                    && let Some(path) = rfd::FileDialog::new().pick_folder()
+                {
+                    self.settings.backup_directory = path.to_string_lossy().to_string();
+                }
+            });
+        }
+    }
+
+    fn render_filename_preview(&mut self, ui: &mut egui::Ui, file: &ImageFile) {
+        // Reading the header every frame would hit the disk while the UI animates
+        let dimensions = match &self.preview_dimensions {
+            Some((path, dimensions)) if *path == file.path => *dimensions,
+            _ => {
+                let dimensions =
+                    CompressionEngine::upright_dimensions(&file.path).unwrap_or((0, 0));
+                self.preview_dimensions = Some((file.path.clone(), dimensions));
+                dimensions
+            }
+        };
+
+        match CompressionEngine::preview_output_path(file, &self.settings, dimensions) {
+            Ok(path) => ui.weak(format!("Preview: {}", path.display())),
+            Err(error) => ui.colored_label(egui::Color32::RED, error.to_string()),
+        };
     }
 
     fn render_compress_button(&mut self, ui: &mut egui::Ui, files: &[ImageFile]) {
@@ -124,19 +635,45 @@ impl CompressionPanel {
             }
         });
 
-        if !can_compress && !files.is_empty() {
-            if let Err(error) = self.settings.validate() {
-                ui.colored_label(egui::Color32::RED, error);
-            }
+        if !can_compress
+            && !files.is_empty()
+            && let Err(error) = self.settings.validate()
+        {
+            ui.colored_label(egui::Color32::RED, error.to_string());
         }
     }
 
     fn render_progress(&self, ui: &mut egui::Ui) {
-        ui.label("Processing...");
+        let cancelling = self.cancel_token.is_cancelled();
+        let paused = self.cancel_token.is_paused();
+
+        if cancelling {
+            ui.label("Cancelling...");
+        } else if paused {
+            ui.label("Paused");
+        } else {
+            ui.label("Processing...");
+        }
         ui.add(egui::ProgressBar::new(self.current_progress).show_percentage());
         if !self.status_message.is_empty() {
             ui.label(&self.status_message);
         }
+
+        ui.add_enabled_ui(!cancelling, |ui| {
+            ui.horizontal(|ui| {
+                if paused {
+                    if ui.button("Resume").clicked() {
+                        self.cancel_token.resume();
+                    }
+                } else if ui.button("Pause").clicked() {
+                    self.cancel_token.pause();
+                }
+
+                if ui.button("Cancel").clicked() {
+                    self.cancel_token.cancel();
+                }
+            });
+        });
     }
 
     fn start_compression(&mut self, files: Vec<ImageFile>) {
@@ -148,9 +685,12 @@ impl CompressionPanel {
 
         let settings = self.settings.clone();
         let result_sender = self.result_sender.clone();
+        self.cancel_token = CancellationToken::new();
+        let cancel_token = self.cancel_token.clone();
 
         std::thread::spawn(move || {
-            let results = CompressionEngine::compress_files(files, settings, progress_sender);
+            let results =
+                CompressionEngine::compress_files(files, settings, progress_sender, &cancel_token);
             if let Some(sender) = result_sender {
                 let _ = sender.send(results);
             }
@@ -177,7 +717,11 @@ impl CompressionPanel {
                     CompressionProgress::Complete => {
                         self.is_processing = false;
                         should_clear_receiver = true;
-                        self.status_message = "Compression completed!".to_string();
+                        self.status_message = if self.cancel_token.is_cancelled() {
+                            "Compression cancelled".to_string()
+                        } else {
+                            "Compression completed!".to_string()
+                        };
                     }
                     CompressionProgress::Error(error) => {
                         self.is_processing = false;
@@ -1,11 +1,23 @@
-use crate::file::{FileManager, ImageFile};
 use eframe::egui;
+use pixelguard::error::PixelGuardError;
+use pixelguard::file::{FileManager, ImageFile, ScanOptions};
+use std::path::PathBuf;
 use std::sync::mpsc;
 
 pub struct FileInput {
     selected_files: Vec<ImageFile>,
-    file_receiver: Option<mpsc::Receiver<Vec<ImageFile>>>,
+    file_receiver: Option<mpsc::Receiver<LoadedFiles>>,
     is_loading: bool,
+    scan_options: ScanOptions,
+    include_patterns: String,
+    exclude_patterns: String,
+    load_error: Option<String>,
+    scanned_folder: Option<PathBuf>,
+}
+
+struct LoadedFiles {
+    folder: Option<PathBuf>,
+    files: Result<Vec<ImageFile>, PixelGuardError>,
 }
 
 impl FileInput {
@@ -14,17 +26,28 @@ impl FileInput {
             selected_files: Vec::new(),
             file_receiver: None,
             is_loading: false,
+            scan_options: ScanOptions::default(),
+            include_patterns: String::new(),
+            exclude_patterns: String::new(),
+            load_error: None,
+            scanned_folder: None,
         }
     }
 
     pub fn render(&mut self, ui: &mut egui::Ui) {
         // Check for completed file loading
-        if let Some(receiver) = &self.file_receiver {
-            if let Ok(files) = receiver.try_recv() {
-                self.selected_files = files;
-                self.file_receiver = None;
-                self.is_loading = false;
+        if let Some(receiver) = &self.file_receiver
+            && let Ok(loaded) = receiver.try_recv()
+        {
+            if loaded.folder.is_some() {
+                self.scanned_folder = loaded.folder;
             }
+            match loaded.files {
+                Ok(files) => self.selected_files = files,
+                Err(error) => self.load_error = Some(error.to_string()),
+            }
+            self.file_receiver = None;
+            self.is_loading = false;
         }
 
         ui.group(|ui| {
@@ -34,14 +57,20 @@ impl FileInput {
                 if ui.button("Browse Files").clicked() && !self.is_loading {
                     self.load_files_async();
                 }
+                if ui.button("Browse Folder").clicked() && !self.is_loading {
+                    self.load_folder_async();
+                }
 
-                if !self.selected_files.is_empty() {
-                    if ui.button("Clear").clicked() {
-                        self.selected_files.clear();
-                    }
+                if !self.selected_files.is_empty() && ui.button("Clear").clicked() {
+                    self.selected_files.clear();
                 }
             });
 
+            self.render_folder_options(ui);
+            if let Some(error) = &self.load_error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+
             if self.is_loading {
                 ui.spinner();
                 ui.label("Loading files...");
@@ -83,25 +112,104 @@ impl FileInput {
         });
     }
 
+    fn render_folder_options(&mut self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Folder options").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Include:");
+                ui.text_edit_singleline(&mut self.include_patterns)
+                    .on_hover_text("Comma separated globs, e.g. *.png, icons/**");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Exclude:");
+                ui.text_edit_singleline(&mut self.exclude_patterns)
+                    .on_hover_text("Comma separated globs, e.g. **/node_modules, *_compressed.*");
+            });
+            ui.horizontal(|ui| {
+                let mut limit_depth = self.scan_options.max_depth.is_some();
+                if ui.checkbox(&mut limit_depth, "Limit depth").changed() {
+                    self.scan_options.max_depth = limit_depth.then_some(1);
+                }
+                if let Some(depth) = &mut self.scan_options.max_depth {
+                    ui.add(egui::DragValue::new(depth).range(0..=64).suffix(" levels"));
+                }
+            });
+            ui.checkbox(
+                &mut self.scan_options.include_hidden,
+                "Include hidden files",
+            );
+            ui.checkbox(
+                &mut self.scan_options.follow_symlinks,
+                "Follow symbolic links",
+            );
+        });
+    }
+
+    fn load_folder_async(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        self.file_receiver = Some(receiver);
+        self.is_loading = true;
+        self.load_error = None;
+
+        let mut options = self.scan_options.clone();
+        options.include = split_patterns(&self.include_patterns);
+        options.exclude = split_patterns(&self.exclude_patterns);
+
+        std::thread::spawn(move || {
+            let folder = rfd::FileDialog::new()
+                .set_title("Select Folder")
+                .pick_folder();
+            let files = match &folder {
+                Some(folder) => FileManager::scan_directory(folder, &options),
+                None => Ok(Vec::new()),
+            };
+            let _ = sender.send(LoadedFiles { folder, files });
+        });
+    }
+
     fn load_files_async(&mut self) {
         let (sender, receiver) = mpsc::channel();
         self.file_receiver = Some(receiver);
         self.is_loading = true;
+        self.load_error = None;
 
         std::thread::spawn(move || {
-            if let Some(paths) = FileManager::open_file_dialog() {
+            let paths = rfd::FileDialog::new()
+                .add_filter("Images", FileManager::SUPPORTED_EXTENSIONS)
+                .set_title("Select Images")
+                .pick_files();
+
+            if let Some(paths) = paths {
                 let files: Vec<ImageFile> = paths
                     .into_iter()
                     .filter_map(FileManager::analyze_file)
                     .collect();
-                let _ = sender.send(files);
+                let _ = sender.send(LoadedFiles {
+                    folder: None,
+                    files: Ok(files),
+                });
             } else {
-                let _ = sender.send(Vec::new());
+                let _ = sender.send(LoadedFiles {
+                    folder: None,
+                    files: Ok(Vec::new()),
+                });
             }
         });
     }
 
+    pub fn take_scanned_folder(&mut self) -> Option<PathBuf> {
+        self.scanned_folder.take()
+    }
+
     pub fn get_selected_files(&self) -> &[ImageFile] {
         &self.selected_files
     }
 }
+
+fn split_patterns(patterns: &str) -> Vec<String> {
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_string)
+        .collect()
+}
@@ -1,10 +1,15 @@
-use crate::compression::{CompressionResult, CompressionStatus};
-use crate::file::FileManager;
 use eframe::egui;
+use pixelguard::compression::{
+    CompressionEngine, CompressionResult, CompressionStatus, QualitySearch,
+};
+use pixelguard::file::FileManager;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
 
 pub struct OutputPanel {
     results: Vec<CompressionResult>,
     show_details: bool,
+    restore_message: Option<Result<String, String>>,
 }
 
 impl OutputPanel {
@@ -12,6 +17,7 @@ impl OutputPanel {
         Self {
             results: Vec::new(),
             show_details: false,
+            restore_message: None,
         }
     }
 
@@ -23,13 +29,32 @@ impl OutputPanel {
                     ui.checkbox(&mut self.show_details, "Show details");
                     if ui.button("Clear").clicked() {
                         self.results.clear();
+                        self.restore_message = None;
                     }
                     if ui.button("Open folder").clicked() {
                         self.open_output_folder();
                     }
+                    if !self.backup_manifests().is_empty()
+                        && ui
+                            .button("Restore originals")
+                            .on_hover_text("Undo in-place compression from the backups")
+                            .clicked()
+                    {
+                        self.restore_originals();
+                    }
                 }
             });
 
+            match &self.restore_message {
+                Some(Ok(message)) => {
+                    ui.colored_label(egui::Color32::GREEN, message);
+                }
+                Some(Err(message)) => {
+                    ui.colored_label(egui::Color32::RED, message);
+                }
+                None => {}
+            }
+
             if self.results.is_empty() {
                 ui.label("No compression results yet");
             } else {
@@ -44,11 +69,7 @@ impl OutputPanel {
     fn render_summary(&self, ui: &mut egui::Ui) {
         let total_original: u64 = self.results.iter().map(|r| r.original_size).sum();
         let total_compressed: u64 = self.results.iter().map(|r| r.compressed_size).sum();
-        let total_saved = if total_original > total_compressed {
-            total_original - total_compressed
-        } else {
-            0
-        };
+        let total_saved = total_original.saturating_sub(total_compressed);
         let avg_ratio = if total_original > 0 {
             1.0 - (total_compressed as f32 / total_original as f32)
         } else {
@@ -72,6 +93,21 @@ impl OutputPanel {
                 avg_ratio * 100.0
             ));
         });
+
+        let mut failures_by_kind = BTreeMap::new();
+        for result in &self.results {
+            if let CompressionStatus::Failed(error) = &result.status {
+                *failures_by_kind.entry(error.kind()).or_insert(0) += 1;
+            }
+        }
+        if !failures_by_kind.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                ui.colored_label(egui::Color32::RED, "Failed:");
+                for (kind, count) in failures_by_kind {
+                    ui.label(format!("{} ({})", kind, count));
+                }
+            });
+        }
     }
 
     fn render_detailed_results(&self, ui: &mut egui::Ui) {
@@ -106,6 +142,9 @@ impl OutputPanel {
             }
 
             ui.label(&filename);
+            if let Some(format) = &result.output_format {
+                ui.weak(format.name().to_uppercase());
+            }
             ui.label(FileManager::format_file_size(result.original_size));
             ui.label("→");
             ui.label(FileManager::format_file_size(result.compressed_size));
@@ -117,23 +156,89 @@ impl OutputPanel {
                 },
                 format!("{:.1}%", result.compression_ratio * 100.0),
             );
+            if let Some(quality) = result.quality {
+                let reason = match result.quality_search {
+                    Some(QualitySearch::VisualTarget) => {
+                        "Lowest quality that reaches the visual quality target"
+                    }
+                    _ => "Highest quality that fits the size limit",
+                };
+                ui.label(format!("q{}", quality)).on_hover_text(reason);
+            }
             ui.label(format!("{:.1}s", result.processing_time.as_secs_f32()));
         });
 
-        if let CompressionStatus::Failed(error) = &result.status {
-            ui.colored_label(egui::Color32::RED, format!("Error: {}", error));
+        if let Some(metrics) = &result.metrics {
+            ui.weak(metrics.to_string()).on_hover_text(
+                "SSIM: 1.0 is identical\nPSNR: above 40 dB is hard to tell apart\nDSSIM: below 0.001 is hard to tell apart",
+            );
+        }
+
+        if !result.metadata_removed.is_empty() {
+            let removed: Vec<_> = result
+                .metadata_removed
+                .iter()
+                .map(|kind| kind.name())
+                .collect();
+            ui.weak(format!("Removed {}", removed.join(", ")));
+        }
+
+        match &result.status {
+            CompressionStatus::Failed(error) => {
+                ui.colored_label(egui::Color32::RED, format!("{}: {}", error.kind(), error));
+            }
+            CompressionStatus::Skipped(reason) => {
+                ui.weak(format!("Skipped: {}", reason));
+            }
+            CompressionStatus::Success => {}
         }
     }
 
-    fn open_output_folder(&self) {
-        if let Some(result) = self.results.first() {
-            if let Some(parent) = result.output_path.parent() {
-                let _ = opener::open(parent);
+    fn backup_manifests(&self) -> BTreeSet<PathBuf> {
+        self.results
+            .iter()
+            .filter_map(|result| result.backup_manifest.clone())
+            .collect()
+    }
+
+    fn restore_originals(&mut self) {
+        let mut restored = 0;
+        let mut errors = Vec::new();
+        for manifest in self.backup_manifests() {
+            match CompressionEngine::restore_backup(&manifest) {
+                Ok(count) => {
+                    restored += count;
+                    for result in &mut self.results {
+                        if result.backup_manifest.as_ref() == Some(&manifest) {
+                            result.backup_manifest = None;
+                        }
+                    }
+                }
+                Err(error) => errors.push(error.to_string()),
             }
         }
+
+        self.restore_message = Some(if errors.is_empty() {
+            Ok(format!("Restored {} original(s)", restored))
+        } else {
+            Err(format!(
+                "Restored {} original(s), failed: {}",
+                restored,
+                errors.join("; ")
+            ))
+        });
+    }
+
+    fn open_output_folder(&self) {
+        if let Some(result) = self.results.first()
+            && let Some(parent) = result.output_path.parent()
+        {
+            let _ = opener::open(parent);
+        }
     }
 
     pub fn add_results(&mut self, results: Vec<CompressionResult>) {
         self.results.extend(results);
+        self.restore_message = None;
     }
 }
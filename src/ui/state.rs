@@ -1,6 +1,6 @@
-use crate::compression::CompressionResult;
 use crate::ui::components::*;
 use eframe::egui;
+use pixelguard::compression::CompressionResult;
 use std::sync::mpsc;
 
 pub struct UiState {
@@ -35,6 +35,9 @@ impl UiState {
 
             ui.vertical_centered(|ui| {
                 self.file_input.render(ui);
+                if let Some(folder) = self.file_input.take_scanned_folder() {
+                    self.compression_panel.set_input_root(&folder);
+                }
                 ui.add_space(20.0);
 
                 let files = self.file_input.get_selected_files();
@@ -47,10 +50,10 @@ impl UiState {
     }
 
     fn update_results(&mut self) {
-        if let Some(receiver) = &self.result_receiver {
-            if let Ok(results) = receiver.try_recv() {
-                self.output_panel.add_results(results);
-            }
+        if let Some(receiver) = &self.result_receiver
+            && let Ok(results) = receiver.try_recv()
+        {
+            self.output_panel.add_results(results);
         }
     }
 }